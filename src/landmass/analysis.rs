@@ -0,0 +1,97 @@
+use super::{WorldMap,Layer,SLOPE,ASPECT,PROFILE_CURVATURE,PLAN_CURVATURE};
+
+// First and second derivatives of height at a cell, from central
+// differences over the grid spacing. Slopes on the border fall back to one
+// sided differences and curvature is taken from the next cell in.
+struct Derivatives {
+    p: f32,
+    q: f32,
+    r: f32,
+    s: f32,
+    t: f32,
+}
+
+impl WorldMap {
+    fn derivatives(&self) -> Vec<Derivatives>{
+        let width = self.parameters.width;
+        let height = self.parameters.height;
+        let d = self.parameters.scale;
+        let heights = self.heights();
+        let h = |r: i32, c: i32| heights[(r.max(0).min(height - 1)*width + c.max(0).min(width - 1)) as usize];
+        // cells spanned by a difference taken either side of n
+        let span = |n: i32, len: i32| ((n + 1).min(len - 1) - (n - 1).max(0)).max(1) as f32;
+        let inner = |n: i32, len: i32| n.max(1).min((len - 2).max(0));
+        let mut out = Vec::with_capacity(heights.len());
+        for i in 0..height{
+            for j in 0..width{
+                let (ic, jc) = (inner(i, height), inner(j, width));
+                out.push(Derivatives{
+                    p: (h(i,j + 1) - h(i,j - 1))/(span(j, width)*d),
+                    q: (h(i + 1,j) - h(i - 1,j))/(span(i, height)*d),
+                    r: (h(i,jc + 1) - 2.0*h(i,jc) + h(i,jc - 1))/(d*d),
+                    s: (h(ic + 1,jc + 1) - h(ic + 1,jc - 1) - h(ic - 1,jc + 1) + h(ic - 1,jc - 1))/(4.0*d*d),
+                    t: (h(ic + 1,j) - 2.0*h(ic,j) + h(ic - 1,j))/(d*d),
+                });
+            }
+        }
+        out
+    }
+
+    // Steepest angle of each cell against the horizontal, in degrees
+    pub fn slope(&self) -> Vec<f32>{
+        self.derivatives().iter()
+            .map(|d| (d.p*d.p + d.q*d.q).sqrt().atan().to_degrees())
+            .collect()
+    }
+
+    // Compass direction the cell faces downhill, degrees clockwise from
+    // north (-z) through east (+x). Flat cells get -1.
+    pub fn aspect(&self) -> Vec<f32>{
+        self.derivatives().iter().map(|d|{
+            if d.p == 0.0 && d.q == 0.0{
+                -1.0
+            } else {
+                let a = (-d.p).atan2(d.q).to_degrees();
+                // due north comes back as -0, which is not below zero
+                if a < 0.0 {a + 360.0} else if a == 0.0 {0.0} else {a}
+            }
+        }).collect()
+    }
+
+    // Curvature along the slope, positive where the ground rolls over and
+    // steepens downhill, negative where it flattens out
+    pub fn profile_curvature(&self) -> Vec<f32>{
+        self.derivatives().iter().map(|d|{
+            let g = d.p*d.p + d.q*d.q;
+            if g == 0.0{
+                0.0
+            } else {
+                -(d.p*d.p*d.r + 2.0*d.p*d.q*d.s + d.q*d.q*d.t)/(g*(1.0 + g).powf(1.5))
+            }
+        }).collect()
+    }
+
+    // Curvature of the contours, positive on spurs where flow spreads out,
+    // negative in hollows where it gathers
+    pub fn plan_curvature(&self) -> Vec<f32>{
+        self.derivatives().iter().map(|d|{
+            let g = d.p*d.p + d.q*d.q;
+            if g == 0.0{
+                0.0
+            } else {
+                -(d.q*d.q*d.r - 2.0*d.p*d.q*d.s + d.p*d.p*d.t)/g.powf(1.5)
+            }
+        }).collect()
+    }
+
+    // Stores slope, aspect and both curvatures as layers, ready for
+    // export_layer
+    pub fn with_terrain_analysis(&self) -> WorldMap{
+        let mut map = self.clone();
+        map.layers.set(SLOPE, Layer::Scalar(self.slope()));
+        map.layers.set(ASPECT, Layer::Scalar(self.aspect()));
+        map.layers.set(PROFILE_CURVATURE, Layer::Scalar(self.profile_curvature()));
+        map.layers.set(PLAN_CURVATURE, Layer::Scalar(self.plan_curvature()));
+        map
+    }
+}
@@ -0,0 +1,38 @@
+use std::time::{Duration,Instant};
+
+use super::{MapParameters,WorldMap,model};
+
+fn seconds(d: Duration) -> f64{
+    d.as_secs() as f64 + d.subsec_nanos() as f64*1e-9
+}
+
+fn time<T,F: FnOnce() -> T>(f: F) -> (T,f64){
+    let start = Instant::now();
+    let out = f();
+    (out, seconds(start.elapsed()))
+}
+
+// Times generation and meshing of a size x size map on one thread and on
+// all of them, and panics unless the two agree bit for bit.
+pub fn run(size: i32, levels: i32, seed: u32){
+    let params = MapParameters::new(size, size, 1.0, levels, 0.5, 2.0);
+    println!("{}x{} map, {} octaves", size, size, levels);
+    let mut results = Vec::new();
+    for &parallel in [false, true].iter(){
+        let (map, generate) = time(|| WorldMap::with_seed(params.with_parallel(parallel), seed));
+        let (model, mesh) = time(|| map.as_model_object());
+        println!("{:>8}: generate {:.3}s, mesh {:.3}s",
+                 if parallel {"parallel"} else {"serial"}, generate, mesh);
+        results.push((map, model));
+    }
+    let (ref serial, ref serial_model) = results[0];
+    let (ref parallel, ref parallel_model) = results[1];
+    let same_heights = serial.heights().iter().map(|h| h.to_bits())
+        .eq(parallel.heights().iter().map(|h| h.to_bits()));
+    let bits = |n: &model::object::Normal| (n.normal.0.to_bits(), n.normal.1.to_bits(), n.normal.2.to_bits());
+    let same_normals = serial_model.normals.as_ref().unwrap().iter().map(&bits)
+        .eq(parallel_model.normals.as_ref().unwrap().iter().map(&bits));
+    let same_index = serial_model.index == parallel_model.index;
+    println!("identical: heights {}, normals {}, indices {}", same_heights, same_normals, same_index);
+    assert!(same_heights && same_normals && same_index, "parallel and serial maps differ");
+}
@@ -0,0 +1,246 @@
+use image;
+use std::fs::File;
+use std::io::{Write,BufWriter};
+use std::path::Path;
+
+use super::{WorldMap,MapError,Layer,TEMPERATURE,MOISTURE,BIOME};
+
+#[derive(Debug,Clone,Copy)]
+pub struct ClimateParameters {
+    // degrees of latitude at the first and last row of the map's extent.
+    // Rows past the extent carry on back and forth between the two, so a
+    // streamed world repeats its climate bands rather than running off to
+    // the poles.
+    pub latitude: (f32,f32),
+    pub equator_temperature: f32,
+    pub pole_temperature: f32,
+    // degrees lost per unit of height above sea level
+    pub lapse_rate: f32,
+    // direction the prevailing wind blows towards, in (x,z)
+    pub wind: (f32,f32),
+    // distance from water over which moisture falls to about a third
+    pub rain_distance: f32,
+    // how far upwind to look for mountains and how much each unit of
+    // height they rise above a cell dries it out
+    pub shadow_distance: f32,
+    pub shadow: f32,
+}
+
+impl ClimateParameters {
+    // How far away terrain and water can still change the climate at a
+    // cell. Water further than twice rain_distance leaves the ground too
+    // dry for any wetter biome, so distances stop counting there.
+    pub fn reach(&self) -> f32{
+        (2.0*self.rain_distance).max(self.shadow_distance)
+    }
+
+    pub fn new(latitude: (f32,f32), wind: (f32,f32)) -> ClimateParameters{
+        ClimateParameters{
+            latitude,
+            equator_temperature: 30.0,
+            pole_temperature: -25.0,
+            lapse_rate: 1.5,
+            wind,
+            rain_distance: 30.0,
+            shadow_distance: 40.0,
+            shadow: 0.3,
+        }
+    }
+}
+
+// stored in the biome layer by position in this list
+#[derive(Debug,Clone,Copy,PartialEq,Eq,Hash)]
+pub enum Biome {
+    Ocean,
+    Lake,
+    Ice,
+    Tundra,
+    BorealForest,
+    TemperateGrassland,
+    Shrubland,
+    TemperateSeasonalForest,
+    TemperateRainforest,
+    SubtropicalDesert,
+    Savanna,
+    TropicalRainforest,
+}
+
+const BIOMES: [Biome;12] = [Biome::Ocean, Biome::Lake, Biome::Ice, Biome::Tundra,
+                            Biome::BorealForest, Biome::TemperateGrassland, Biome::Shrubland,
+                            Biome::TemperateSeasonalForest, Biome::TemperateRainforest,
+                            Biome::SubtropicalDesert, Biome::Savanna, Biome::TropicalRainforest];
+
+impl Biome {
+    pub fn from_index(index: u8) -> Option<Biome>{
+        BIOMES.get(index as usize).cloned()
+    }
+
+    // Whittaker style split of temperature (degrees) against moisture (0..1)
+    pub fn classify(temperature: f32, moisture: f32) -> Biome{
+        if temperature < -10.0{
+            Biome::Ice
+        } else if temperature < 0.0{
+            Biome::Tundra
+        } else if temperature < 8.0{
+            if moisture < 0.25 {Biome::TemperateGrassland} else {Biome::BorealForest}
+        } else if temperature < 20.0{
+            if moisture < 0.2{
+                Biome::TemperateGrassland
+            } else if moisture < 0.4{
+                Biome::Shrubland
+            } else if moisture < 0.7{
+                Biome::TemperateSeasonalForest
+            } else {
+                Biome::TemperateRainforest
+            }
+        } else if moisture < 0.25{
+            Biome::SubtropicalDesert
+        } else if moisture < 0.55{
+            Biome::Savanna
+        } else {
+            Biome::TropicalRainforest
+        }
+    }
+
+    pub fn color(&self) -> [u8;3]{
+        match *self{
+            Biome::Ocean => [38, 70, 140],
+            Biome::Lake => [60, 110, 170],
+            Biome::Ice => [235, 240, 245],
+            Biome::Tundra => [150, 160, 140],
+            Biome::BorealForest => [70, 100, 70],
+            Biome::TemperateGrassland => [170, 175, 110],
+            Biome::Shrubland => [150, 140, 90],
+            Biome::TemperateSeasonalForest => [80, 130, 60],
+            Biome::TemperateRainforest => [40, 110, 70],
+            Biome::SubtropicalDesert => [215, 190, 130],
+            Biome::Savanna => [165, 165, 65],
+            Biome::TropicalRainforest => [30, 120, 40],
+        }
+    }
+}
+
+impl WorldMap {
+    // Fills the temperature, moisture and biome layers. Lakes and the
+    // distance to water only take in this map, not whatever lies past its
+    // edges, so a window onto a larger world needs params.reach() of
+    // terrain around it to match its neighbours.
+    pub fn with_climate(&self, params: &ClimateParameters) -> WorldMap{
+        let width = self.parameters.width as usize;
+        let height = self.parameters.height as usize;
+        let scale = self.parameters.scale;
+        let sea_level = self.parameters.sea_level;
+        let heights = self.heights();
+        let lakes = self.lake_levels();
+        let sea: Vec<bool> = heights.iter().map(|&h| h <= sea_level).collect();
+        let water: Vec<bool> = sea.iter().zip(&lakes).map(|(&s,l)| s || l.is_some()).collect();
+
+        // latitude runs along z and follows the world position, so chunks
+        // of one map agree on it
+        let (_,oz) = self.parameters.origin;
+        let rows = (self.parameters.extent.1 - 1.0).max(1.0);
+        let mut temperature = Vec::with_capacity(heights.len());
+        for i in 0..height{
+            let t = (oz + i as i32) as f32 / rows;
+            let t = t - 2.0*(t/2.0).floor();
+            let t = if t > 1.0 {2.0 - t} else {t};
+            let latitude = (params.latitude.0 + (params.latitude.1 - params.latitude.0)*t).clamp(-90.0, 90.0);
+            let base = params.equator_temperature
+                + (params.pole_temperature - params.equator_temperature)*latitude.abs()/90.0;
+            for j in 0..width{
+                let altitude = (heights[i*width + j] - sea_level).max(0.0);
+                temperature.push(base - params.lapse_rate*altitude);
+            }
+        }
+
+        let distance = distance_to(&water, width, height);
+        let dry = 2.0*params.rain_distance/scale;
+        let (wx,wz) = params.wind;
+        let wind = (wx*wx + wz*wz).sqrt();
+        let steps = if wind > 0.0 {(params.shadow_distance/scale) as i32} else {0};
+        let mut moisture = Vec::with_capacity(heights.len());
+        for i in 0..height{
+            for j in 0..width{
+                let idx = i*width + j;
+                let wet = (-distance[idx].min(dry)*scale/params.rain_distance).exp();
+                // tallest rise between this cell and the wind coming to it
+                let mut barrier = 0.0f32;
+                for k in 1..(steps + 1){
+                    let r = (i as f32 - wz/wind*k as f32).round() as i32;
+                    let c = (j as f32 - wx/wind*k as f32).round() as i32;
+                    if r < 0 || c < 0 || r >= height as i32 || c >= width as i32{
+                        break;
+                    }
+                    barrier = barrier.max(heights[r as usize*width + c as usize] - heights[idx]);
+                }
+                moisture.push(wet*(-params.shadow*barrier).exp());
+            }
+        }
+
+        let biomes = (0..heights.len()).map(|idx|{
+            let biome = if sea[idx]{
+                Biome::Ocean
+            } else if lakes[idx].is_some(){
+                Biome::Lake
+            } else {
+                Biome::classify(temperature[idx], moisture[idx])
+            };
+            biome as u8
+        }).collect();
+        let mut map = self.clone();
+        map.layers.set(TEMPERATURE, Layer::Scalar(temperature));
+        map.layers.set(MOISTURE, Layer::Scalar(moisture));
+        map.layers.set(BIOME, Layer::Category8(biomes));
+        map
+    }
+
+    pub fn export_biomes<P: AsRef<Path>>(&self, path: P) -> Result<(),MapError>{
+        let biomes = match self.layers.category8(BIOME){
+            Some(biomes) => biomes,
+            None => return Err(MapError::Format("map has no biome layer to export".to_string())),
+        };
+        let mut file = BufWriter::new(File::create(path)?);
+        let bytes: Vec<u8> = biomes.iter()
+            .flat_map(|&b| Biome::from_index(b).map_or([0, 0, 0], |b| b.color()).to_vec())
+            .collect();
+        image::png::PNGEncoder::new(&mut file).encode(&bytes,
+                                                      self.parameters.width as u32,
+                                                      self.parameters.height as u32,
+                                                      image::RGB(8))?;
+        file.flush()?;
+        Ok(())
+    }
+}
+
+// Chamfer distance in cells from every cell to the nearest marked one
+fn distance_to(mask: &[bool], width: usize, height: usize) -> Vec<f32>{
+    let diagonal = 2f32.sqrt();
+    let mut d: Vec<f32> = mask.iter().map(|&m| if m {0.0} else {f32::INFINITY}).collect();
+    let relax = |d: &mut [f32], i: usize, j: usize, r: i32, c: i32, w: f32|{
+        if r >= 0 && c >= 0 && (r as usize) < height && (c as usize) < width{
+            let n = d[r as usize*width + c as usize] + w;
+            if n < d[i*width + j]{
+                d[i*width + j] = n;
+            }
+        }
+    };
+    for i in 0..height{
+        for j in 0..width{
+            let (r,c) = (i as i32, j as i32);
+            relax(&mut d, i, j, r - 1, c - 1, diagonal);
+            relax(&mut d, i, j, r - 1, c, 1.0);
+            relax(&mut d, i, j, r - 1, c + 1, diagonal);
+            relax(&mut d, i, j, r, c - 1, 1.0);
+        }
+    }
+    for i in (0..height).rev(){
+        for j in (0..width).rev(){
+            let (r,c) = (i as i32, j as i32);
+            relax(&mut d, i, j, r + 1, c + 1, diagonal);
+            relax(&mut d, i, j, r + 1, c, 1.0);
+            relax(&mut d, i, j, r + 1, c - 1, diagonal);
+            relax(&mut d, i, j, r, c + 1, 1.0);
+        }
+    }
+    d
+}
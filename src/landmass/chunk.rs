@@ -0,0 +1,122 @@
+use std::collections::HashMap;
+
+use super::{MapParameters,WorldMap,Graph,Source,ClimateParameters,stream};
+use super::lod::{Lod,LodKey};
+
+pub type ChunkKey = (i32,i32);
+
+// Streams square chunks of the global noise field around a moving point.
+// Each chunk covers size x size cells and carries size+1 vertices a side, so
+// neighbouring chunks share their border row of vertices.
+#[derive(Debug)]
+pub struct Chunks {
+    pub seed: u32,
+    params: MapParameters,
+    size: i32,
+    radius: i32,
+    graph: Option<Graph>,
+    climate: Option<ClimateParameters>,
+    chunks: HashMap<ChunkKey,WorldMap>,
+}
+
+impl Chunks {
+    pub fn new(params: MapParameters, size: i32, radius: i32) -> Chunks{
+        let seed = params.seed_or_random();
+        Chunks{
+            seed,
+            params: params.seeded(seed),
+            size,
+            radius,
+            graph: None,
+            climate: None,
+            chunks: HashMap::new(),
+        }
+    }
+
+    // Samples the graph in place of the noise. The graph is read at a
+    // single level so the recipe comes out as written rather than summed
+    // over the octaves in params.
+    pub fn with_graph(mut self, graph: Graph) -> Chunks{
+        self.params.levels = 1;
+        self.graph = Some(graph);
+        self
+    }
+
+    // Gives every chunk a climate and biomes. The climate is worked out
+    // over the chunk and climate.reach() of terrain around it, then cut
+    // down to the chunk, so neighbours agree on the vertices they share.
+    // Lakes are still flooded from the edge of that wider window, so a
+    // basin larger than the margin can end at a different level either side.
+    pub fn with_climate(mut self, climate: ClimateParameters) -> Chunks{
+        self.climate = Some(climate);
+        self
+    }
+
+    pub fn key(&self, position: [f32;3]) -> ChunkKey{
+        let span = self.size as f32 * self.params.scale;
+        ((position[0] / span).floor() as i32,
+         (position[2] / span).floor() as i32)
+    }
+
+    pub fn get(&self, key: &ChunkKey) -> Option<&WorldMap>{
+        self.chunks.get(key)
+    }
+
+    pub fn generate(&self, key: ChunkKey) -> WorldMap{
+        let origin = (key.0*self.size, key.1*self.size);
+        let side = self.size + 1;
+        let source = Source::new(self.params.noise, self.seed);
+        match self.climate{
+            Some(ref climate) => {
+                // sampled with a margin the climate can see into, cropping
+                // leaves a ring of it behind as the apron
+                let margin = ((climate.reach()/self.params.scale).ceil() as i32).max(1);
+                let params = self.params.region((origin.0 - margin, origin.1 - margin), side + 2*margin, side + 2*margin);
+                let map = match self.graph{
+                    Some(ref graph) => WorldMap::from_source(params, self.seed, graph),
+                    None => WorldMap::from_source(params, self.seed, &source)
+                };
+                let m = margin as usize;
+                map.with_climate(climate).crop(m, m, side as usize, side as usize)
+            },
+            None => {
+                // the apron keeps the lighting continuous across chunk borders
+                let params = self.params.region(origin, side, side);
+                match self.graph{
+                    Some(ref graph) => WorldMap::from_source_with_apron(params, self.seed, graph),
+                    None => WorldMap::from_source_with_apron(params, self.seed, &source)
+                }
+            }
+        }
+    }
+
+    // Loads every chunk within radius of position and drops the ones that
+    // fell out of range. Returns the keys that were added and removed.
+    pub fn update(&mut self, position: [f32;3]) -> (Vec<ChunkKey>,Vec<ChunkKey>){
+        let (cx,cz) = self.key(position);
+        let radius = self.radius;
+        let wanted: Vec<ChunkKey> = ((cx - radius)..(cx + radius + 1))
+            .flat_map(|x| ((cz - radius)..(cz + radius + 1)).map(move |z| (x,z)))
+            .collect();
+        // taken out while generating, which needs the rest of self
+        let mut chunks = std::mem::take(&mut self.chunks);
+        let changed = stream(&mut chunks, &wanted, |key| self.generate(key));
+        self.chunks = chunks;
+        changed
+    }
+
+    pub fn distance(&self, key: ChunkKey, position: [f32;3]) -> f32{
+        let span = self.size as f32 * self.params.scale;
+        let dx = (key.0 as f32 + 0.5)*span - position[0];
+        let dz = (key.1 as f32 + 0.5)*span - position[2];
+        (dx*dx + dz*dz).sqrt()
+    }
+
+    // Level for the chunk along with the levels its four neighbours will pick
+    // from the same position, so shared edges can be stitched.
+    pub fn lod_key(&self, lod: &Lod, key: ChunkKey, position: [f32;3]) -> LodKey{
+        let (x,z) = key;
+        let level = |k: ChunkKey| lod.level(self.distance(k, position));
+        (level(key), [level((x, z - 1)), level((x + 1, z)), level((x, z + 1)), level((x - 1, z))])
+    }
+}
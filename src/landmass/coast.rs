@@ -0,0 +1,184 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{Write,BufWriter};
+use std::path::Path;
+
+use super::{WorldMap,MapError};
+
+// world space (x,z) points
+pub type Polyline = Vec<(f32,f32)>;
+
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum Falloff {
+    // distance from the centre, reaching 1 at the middle of each edge
+    Radial,
+    // distance to the nearest edge, 1 along the whole border
+    Edge,
+}
+
+// a point on the grid edge leaving (row,col), across if true, down if not
+type EdgeId = (i32,i32,bool);
+
+impl WorldMap {
+    pub fn land_mask(&self) -> Vec<bool>{
+        let sea_level = self.parameters.sea_level;
+        self.vertecies.iter().map(|v| v.position.1 > sea_level).collect()
+    }
+
+    pub fn falloff_mask(&self, falloff: Falloff) -> Vec<f32>{
+        let width = self.parameters.width;
+        let height = self.parameters.height;
+        let mut mask = Vec::with_capacity((width*height) as usize);
+        for i in 0..height{
+            for j in 0..width{
+                let dx = 2.0*j as f32/((width - 1).max(1) as f32) - 1.0;
+                let dz = 2.0*i as f32/((height - 1).max(1) as f32) - 1.0;
+                let d = match falloff{
+                    Falloff::Radial => (dx*dx + dz*dz).sqrt().min(1.0),
+                    Falloff::Edge => dx.abs().max(dz.abs()),
+                };
+                mask.push(d);
+            }
+        }
+        mask
+    }
+
+    // Pulls the land down towards the border so the map ends up an island
+    // ringed by sea. Heights drop by strength at the falloff's edge.
+    pub fn island(&self, falloff: Falloff, strength: f32) -> WorldMap{
+        let heights = self.heights().iter()
+            .zip(self.falloff_mask(falloff))
+            .map(|(h,d)| h - strength*d*d)
+            .collect();
+        self.with_heights(heights)
+    }
+
+    // Coastlines traced with marching squares at sea level. Lines that meet
+    // the edge of the map are left open, islands and lakes close on their
+    // first point.
+    pub fn coastlines(&self) -> Vec<Polyline>{
+        let width = self.parameters.width;
+        let height = self.parameters.height;
+        let sea_level = self.parameters.sea_level;
+        let h = |r: i32, c: i32| self.vertecies[(r*width + c) as usize].position.1 - sea_level;
+        let mut segments: Vec<(EdgeId,EdgeId)> = Vec::new();
+        for r in 0..(height - 1){
+            for c in 0..(width - 1){
+                let corners = [h(r,c), h(r,c + 1), h(r + 1,c + 1), h(r + 1,c)];
+                let case = corners.iter().enumerate()
+                    .fold(0, |acc, (n,&v)| if v > 0.0 {acc | 1 << n} else {acc});
+                let top = (r,c,true);
+                let right = (r,c + 1,false);
+                let bottom = (r + 1,c,true);
+                let left = (r,c,false);
+                let centre = corners.iter().sum::<f32>() > 0.0;
+                match case{
+                    0 | 15 => (),
+                    1 | 14 => segments.push((left,top)),
+                    2 | 13 => segments.push((top,right)),
+                    3 | 12 => segments.push((left,right)),
+                    4 | 11 => segments.push((right,bottom)),
+                    6 | 9 => segments.push((top,bottom)),
+                    7 | 8 => segments.push((left,bottom)),
+                    // saddles, split by the value in the middle of the cell
+                    5 => if centre {
+                        segments.push((left,bottom));
+                        segments.push((top,right));
+                    } else {
+                        segments.push((left,top));
+                        segments.push((right,bottom));
+                    },
+                    _ => if centre {
+                        segments.push((left,top));
+                        segments.push((right,bottom));
+                    } else {
+                        segments.push((left,bottom));
+                        segments.push((top,right));
+                    },
+                }
+            }
+        }
+        let point = |e: EdgeId| -> (f32,f32){
+            let (r,c,across) = e;
+            let (r1,c1) = if across {(r,c + 1)} else {(r + 1,c)};
+            let (a,b) = (h(r,c), h(r1,c1));
+            let t = if a != b {a/(a - b)} else {0.5};
+            let pa = self.vertecies[(r*width + c) as usize].position;
+            let pb = self.vertecies[(r1*width + c1) as usize].position;
+            (pa.0 + (pb.0 - pa.0)*t, pa.2 + (pb.2 - pa.2)*t)
+        };
+        join(&segments).into_iter()
+            .map(|line| line.into_iter().map(&point).collect())
+            .collect()
+    }
+
+    pub fn export_coastlines_svg<P: AsRef<Path>>(&self, path: P) -> Result<(),MapError>{
+        let scale = self.parameters.scale;
+        let (ox,oz) = self.parameters.origin;
+        let mut file = BufWriter::new(File::create(path)?);
+        writeln!(file, "<svg xmlns=\"http://www.w3.org/2000/svg\" viewBox=\"{} {} {} {}\">",
+                 ox as f32*scale, oz as f32*scale,
+                 (self.parameters.width - 1) as f32*scale,
+                 (self.parameters.height - 1) as f32*scale)?;
+        for line in self.coastlines(){
+            let points: Vec<String> = line.iter().map(|&(x,z)| format!("{},{}", x, z)).collect();
+            writeln!(file, "<polyline fill=\"none\" stroke=\"black\" points=\"{}\"/>", points.join(" "))?;
+        }
+        writeln!(file, "</svg>")?;
+        file.flush()?;
+        Ok(())
+    }
+
+    pub fn export_coastlines_geojson<P: AsRef<Path>>(&self, path: P) -> Result<(),MapError>{
+        write_geojson(path, &self.coastlines())
+    }
+}
+
+// Chains segments that share an edge point into polylines
+fn join(segments: &[(EdgeId,EdgeId)]) -> Vec<Vec<EdgeId>>{
+    let mut touching: HashMap<EdgeId,Vec<usize>> = HashMap::new();
+    for (n,&(a,b)) in segments.iter().enumerate(){
+        touching.entry(a).or_default().push(n);
+        touching.entry(b).or_default().push(n);
+    }
+    let mut used = vec![false; segments.len()];
+    let mut lines = Vec::new();
+    for start in 0..segments.len(){
+        if used[start]{
+            continue;
+        }
+        used[start] = true;
+        let (a,b) = segments[start];
+        let mut forward = vec![a,b];
+        walk(b, &mut forward, &mut used, &touching, segments);
+        let mut backward = Vec::new();
+        walk(a, &mut backward, &mut used, &touching, segments);
+        backward.reverse();
+        backward.extend(forward);
+        lines.push(backward);
+    }
+    lines
+}
+
+fn walk(from: EdgeId, line: &mut Vec<EdgeId>, used: &mut [bool],
+        touching: &HashMap<EdgeId,Vec<usize>>, segments: &[(EdgeId,EdgeId)]){
+    let mut at = from;
+    while let Some(&n) = touching[&at].iter().find(|&&n| !used[n]){
+        used[n] = true;
+        let (p,q) = segments[n];
+        at = if p == at {q} else {p};
+        line.push(at);
+    }
+}
+
+pub fn write_geojson<P: AsRef<Path>>(path: P, lines: &[Polyline]) -> Result<(),MapError>{
+    let mut file = BufWriter::new(File::create(path)?);
+    let features: Vec<String> = lines.iter().map(|line|{
+        let coords: Vec<String> = line.iter().map(|&(x,z)| format!("[{},{}]", x, z)).collect();
+        format!("{{\"type\":\"Feature\",\"properties\":{{}},\"geometry\":{{\"type\":\"LineString\",\"coordinates\":[{}]}}}}",
+                coords.join(","))
+    }).collect();
+    writeln!(file, "{{\"type\":\"FeatureCollection\",\"features\":[{}]}}", features.join(","))?;
+    file.flush()?;
+    Ok(())
+}
@@ -0,0 +1,85 @@
+use rand::Rng;
+
+use super::{WorldMap,seeded_rng};
+
+#[derive(Debug,Clone,Copy)]
+pub struct CraterParameters {
+    pub count: usize,
+    // rim radius range in cells
+    pub min_radius: f32,
+    pub max_radius: f32,
+    // the number of craters larger than r goes as r^-exponent, so small ones
+    // far outnumber big ones
+    pub exponent: f32,
+    // bowl depth and rim height as fractions of the radius
+    pub depth: f32,
+    pub rim: f32,
+    // how far the ejecta blanket reaches, in radii from the centre
+    pub ejecta: f32,
+    // craters at least this wide get a central peak
+    pub peak_radius: f32,
+    pub seed: u32,
+}
+
+impl CraterParameters {
+    pub fn new(count: usize, seed: u32) -> CraterParameters{
+        CraterParameters{
+            count,
+            min_radius: 2.0,
+            max_radius: 40.0,
+            exponent: 2.0,
+            depth: 0.4,
+            rim: 0.08,
+            ejecta: 3.0,
+            peak_radius: 15.0,
+            seed,
+        }
+    }
+}
+
+impl WorldMap {
+    // Stamps craters one after another, so later ones cut into earlier
+    // ones. Inside the rim the bowl gradually replaces the ground at the
+    // centre, outside it the ejecta is laid on top of whatever is there.
+    pub fn with_craters(&self, params: &CraterParameters) -> WorldMap{
+        let (w,h) = (self.parameters.width as usize, self.parameters.height as usize);
+        let scale = self.parameters.scale;
+        let mut heights = self.heights();
+        if w == 0 || h == 0{
+            return self.with_heights(heights);
+        }
+        let mut rng = seeded_rng(params.seed);
+        let (lo,hi) = (params.min_radius.max(0.5), params.max_radius.max(params.min_radius.max(0.5)));
+        let a = params.exponent;
+        for _ in 0..params.count{
+            let (cx,cz) = (rng.gen_range(0.0, w as f32), rng.gen_range(0.0, h as f32));
+            // inverse of the power law truncated to lo..hi
+            let u: f32 = rng.gen();
+            let r = if a == 0.0 {lo + (hi - lo)*u} else {(lo.powf(-a) - u*(lo.powf(-a) - hi.powf(-a))).powf(-1.0/a)};
+            let depth = params.depth*r*scale;
+            let rim = params.rim*r*scale;
+            let peak = if r >= params.peak_radius {depth*0.5} else {0.0};
+            let centre = heights[(cz as usize).min(h - 1)*w + (cx as usize).min(w - 1)];
+            let reach = r*params.ejecta.max(1.0);
+            let rows = ((cz - reach).max(0.0) as usize)..((cz + reach).ceil().min(h as f32) as usize);
+            for i in rows{
+                for j in ((cx - reach).max(0.0) as usize)..((cx + reach).ceil().min(w as f32) as usize){
+                    let x = ((j as f32 - cx).powi(2) + (i as f32 - cz).powi(2)).sqrt()/r;
+                    let cell = &mut heights[i*w + j];
+                    if x < 1.0{
+                        // parabolic bowl from -depth at the centre up to the rim
+                        let bowl = (depth + rim)*x*x - depth + peak*(-(x/0.25)*(x/0.25)).exp();
+                        let erase = 1.0 - x*x;
+                        *cell = (*cell + bowl)*(1.0 - erase) + (centre + bowl)*erase;
+                    } else if x < params.ejecta{
+                        // ejecta thins with the cube of distance and fades to
+                        // nothing at its edge
+                        let fade = 1.0 - ((x - 1.0)/(params.ejecta - 1.0)).powi(2);
+                        *cell += rim*x.powi(-3)*fade;
+                    }
+                }
+            }
+        }
+        self.with_heights(heights)
+    }
+}
@@ -0,0 +1,213 @@
+use rand::Rng;
+
+use super::{WorldMap,Layer,SEDIMENT,seeded_rng};
+
+#[derive(Debug,Clone,Copy)]
+pub struct HydraulicParameters {
+    pub iterations: usize,
+    pub lifetime: usize,
+    pub inertia: f32,
+    pub capacity: f32,
+    pub min_capacity: f32,
+    pub erosion: f32,
+    pub deposition: f32,
+    pub evaporation: f32,
+    pub gravity: f32,
+    pub seed: u32,
+}
+
+impl HydraulicParameters {
+    pub fn new(iterations: usize, seed: u32) -> HydraulicParameters{
+        HydraulicParameters{
+            iterations,
+            lifetime: 30,
+            inertia: 0.05,
+            capacity: 4.0,
+            min_capacity: 0.01,
+            erosion: 0.3,
+            deposition: 0.3,
+            evaporation: 0.01,
+            gravity: 4.0,
+            seed,
+        }
+    }
+}
+
+#[derive(Debug,Clone,Copy)]
+pub struct ThermalParameters {
+    pub iterations: usize,
+    pub talus: f32,
+    pub rate: f32,
+}
+
+impl ThermalParameters {
+    // talus is the angle of repose in degrees
+    pub fn new(iterations: usize, talus: f32) -> ThermalParameters{
+        ThermalParameters{iterations, talus, rate: 0.5}
+    }
+}
+
+struct Grid {
+    width: usize,
+    height: usize,
+    heights: Vec<f32>,
+}
+
+impl Grid {
+    // bilinear height and gradient at a point inside the grid
+    fn sample(&self, x: f32, y: f32) -> (f32,f32,f32){
+        let (i,j) = (x as usize, y as usize);
+        let (u,v) = (x - i as f32, y - j as f32);
+        let idx = j*self.width + i;
+        let nw = self.heights[idx];
+        let ne = self.heights[idx + 1];
+        let sw = self.heights[idx + self.width];
+        let se = self.heights[idx + self.width + 1];
+        let gx = (ne - nw)*(1.0 - v) + (se - sw)*v;
+        let gy = (sw - nw)*(1.0 - u) + (se - ne)*u;
+        let h = nw*(1.0 - u)*(1.0 - v) + ne*u*(1.0 - v) + sw*(1.0 - u)*v + se*u*v;
+        (h,gx,gy)
+    }
+
+    // spread amount over the four corners of the cell containing (x,y)
+    fn deposit(&mut self, x: f32, y: f32, amount: f32){
+        let (i,j) = (x as usize, y as usize);
+        let (u,v) = (x - i as f32, y - j as f32);
+        let idx = j*self.width + i;
+        let w = self.width;
+        self.heights[idx] += amount*(1.0 - u)*(1.0 - v);
+        self.heights[idx + 1] += amount*u*(1.0 - v);
+        self.heights[idx + w] += amount*(1.0 - u)*v;
+        self.heights[idx + w + 1] += amount*u*v;
+    }
+
+    fn inside(&self, x: f32, y: f32) -> bool{
+        x >= 0.0 && y >= 0.0 &&
+            x < (self.width - 1) as f32 && y < (self.height - 1) as f32
+    }
+}
+
+impl WorldMap {
+    // Droplet based hydraulic erosion. Each droplet follows the gradient,
+    // picking up sediment while it accelerates downhill and dropping it
+    // when it slows or climbs.
+    pub fn hydraulic_erosion(&self, params: &HydraulicParameters) -> WorldMap{
+        let mut grid = Grid{
+            width: self.parameters.width as usize,
+            height: self.parameters.height as usize,
+            heights: self.heights(),
+        };
+        if grid.width < 2 || grid.height < 2 {
+            return self.with_heights(grid.heights);
+        }
+        let mut rng = seeded_rng(params.seed);
+        for _ in 0..params.iterations{
+            let mut x = rng.gen_range(0.0, (grid.width - 1) as f32);
+            let mut y = rng.gen_range(0.0, (grid.height - 1) as f32);
+            let (mut dx, mut dy) = (0.0f32, 0.0f32);
+            let mut speed = 1.0f32;
+            let mut water = 1.0f32;
+            let mut sediment = 0.0f32;
+            for _ in 0..params.lifetime{
+                let (h,gx,gy) = grid.sample(x,y);
+                dx = dx*params.inertia - gx*(1.0 - params.inertia);
+                dy = dy*params.inertia - gy*(1.0 - params.inertia);
+                let len = (dx*dx + dy*dy).sqrt();
+                if len == 0.0{
+                    break;
+                }
+                dx /= len;
+                dy /= len;
+                let (ox,oy) = (x,y);
+                x += dx;
+                y += dy;
+                if !grid.inside(x,y){
+                    break;
+                }
+                let (nh,_,_) = grid.sample(x,y);
+                let dh = nh - h;
+                let capacity = (-dh*speed*water*params.capacity).max(params.min_capacity);
+                if sediment > capacity || dh > 0.0{
+                    let amount = if dh > 0.0{
+                        dh.min(sediment)
+                    } else {
+                        (sediment - capacity)*params.deposition
+                    };
+                    sediment -= amount;
+                    grid.deposit(ox,oy,amount);
+                } else {
+                    let amount = ((capacity - sediment)*params.erosion).min(-dh);
+                    sediment += amount;
+                    grid.deposit(ox,oy,-amount);
+                }
+                speed = (speed*speed - dh*params.gravity).max(0.0).sqrt();
+                water *= 1.0 - params.evaporation;
+            }
+            // droplets that evaporate on the map leave their load behind
+            if grid.inside(x,y){
+                grid.deposit(x,y,sediment);
+            }
+        }
+        // net material laid down (positive) or carried off (negative)
+        let sediment = grid.heights.iter().zip(self.heights()).map(|(new,old)| new - old).collect();
+        let mut map = self.with_heights(grid.heights);
+        map.layers.set(SEDIMENT, Layer::Scalar(sediment));
+        map
+    }
+
+    // Thermal erosion. Wherever the drop to a neighbour is steeper than the
+    // talus angle, part of the excess slides downhill, split between the
+    // offending neighbours in proportion to how far past the angle they are.
+    pub fn thermal_erosion(&self, params: &ThermalParameters) -> WorldMap{
+        let width = self.parameters.width as isize;
+        let height = self.parameters.height as isize;
+        let scale = self.parameters.scale;
+        let talus = params.talus.to_radians().tan();
+        let neighbours = [(-1,-1),(0,-1),(1,-1),(-1,0),(1,0),(-1,1),(0,1),(1,1)];
+        let mut heights = self.heights();
+        let mut delta = vec![0.0f32; heights.len()];
+        for _ in 0..params.iterations{
+            for d in delta.iter_mut(){
+                *d = 0.0;
+            }
+            for j in 0..height{
+                for i in 0..width{
+                    let idx = (j*width + i) as usize;
+                    let h = heights[idx];
+                    let mut excess = [0.0f32; 8];
+                    let mut total = 0.0;
+                    let mut max = 0.0f32;
+                    for (n,&(di,dj)) in neighbours.iter().enumerate(){
+                        let (ni,nj) = (i + di, j + dj);
+                        if ni < 0 || nj < 0 || ni >= width || nj >= height{
+                            continue;
+                        }
+                        let distance = if di != 0 && dj != 0 {scale*2f32.sqrt()} else {scale};
+                        let drop = h - heights[(nj*width + ni) as usize];
+                        let over = drop - talus*distance;
+                        if over > 0.0{
+                            excess[n] = over;
+                            total += over;
+                            max = max.max(over);
+                        }
+                    }
+                    if total == 0.0{
+                        continue;
+                    }
+                    let moved = params.rate*max*0.5;
+                    delta[idx] -= moved;
+                    for (n,&(di,dj)) in neighbours.iter().enumerate(){
+                        if excess[n] > 0.0{
+                            let nidx = ((j + dj)*width + i + di) as usize;
+                            delta[nidx] += moved*excess[n]/total;
+                        }
+                    }
+                }
+            }
+            for (h,d) in heights.iter_mut().zip(delta.iter()){
+                *h += *d;
+            }
+        }
+        self.with_heights(heights)
+    }
+}
@@ -0,0 +1,317 @@
+use noise::{Perlin,OpenSimplex,Worley,Value,Seedable,NoiseFn};
+use ron;
+use std::cmp::Ordering;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+use super::MapError;
+use super::shaping::{Curve,terrace};
+
+// A terrain recipe, written out as a RON tree, e.g.
+//
+//     Max(
+//         Perlin(frequency: 2.0),
+//         Terrace(source: Worley(seed: 3), steps: [-1.0, 0.0, 0.5, 1.0]),
+//     )
+//
+// Seeds on the sources are offsets from the map seed, so one recipe can
+// still produce many different worlds.
+#[derive(Debug,Clone,Deserialize)]
+pub enum Node {
+    Perlin {
+        #[serde(default)]
+        seed: u32,
+        #[serde(default = "unit")]
+        frequency: f64,
+    },
+    OpenSimplex {
+        #[serde(default)]
+        seed: u32,
+        #[serde(default = "unit")]
+        frequency: f64,
+    },
+    Worley {
+        #[serde(default)]
+        seed: u32,
+        #[serde(default = "unit")]
+        frequency: f64,
+    },
+    Value {
+        #[serde(default)]
+        seed: u32,
+        #[serde(default = "unit")]
+        frequency: f64,
+    },
+    Constant(f64),
+    Add(Box<Node>,Box<Node>),
+    Mul(Box<Node>,Box<Node>),
+    Min(Box<Node>,Box<Node>),
+    Max(Box<Node>,Box<Node>),
+    // picks b where control is above threshold and a elsewhere, blending
+    // over a band falloff wide either side of the threshold. With no
+    // falloff it switches hard, taking b from the threshold up.
+    Select {
+        control: Box<Node>,
+        a: Box<Node>,
+        b: Box<Node>,
+        #[serde(default)]
+        threshold: f64,
+        #[serde(default)]
+        falloff: f64,
+    },
+    // control of -1 gives a, 1 gives b
+    Blend {
+        control: Box<Node>,
+        a: Box<Node>,
+        b: Box<Node>,
+    },
+    // remap through (input, output) points along the same monotone cubic
+    // as WorldMap::remap
+    Curve {
+        source: Box<Node>,
+        points: Vec<(f64,f64)>,
+    },
+    // shelves at each step with a rise that steepens towards the next one,
+    // as WorldMap::terrace at sharpness 0.5
+    Terrace {
+        source: Box<Node>,
+        steps: Vec<f64>,
+    },
+    Clamp {
+        source: Box<Node>,
+        min: f64,
+        max: f64,
+    },
+    Abs(Box<Node>),
+    ScaleBias {
+        source: Box<Node>,
+        scale: f64,
+        bias: f64,
+    },
+}
+
+fn unit() -> f64{
+    1.0
+}
+
+impl Node {
+    pub fn from_ron(text: &str) -> Result<Node,MapError>{
+        ron::de::from_str(text).map_err(|e| MapError::Format(e.to_string()))
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Node,MapError>{
+        let mut text = String::new();
+        File::open(path)?.read_to_string(&mut text)?;
+        Node::from_ron(&text)
+    }
+
+    pub fn build(&self, seed: u32) -> Graph{
+        let build = |n: &Node| Box::new(n.build(seed));
+        match *self{
+            Node::Perlin{seed: s, frequency} =>
+                Graph::Perlin(Perlin::new().set_seed(seed.wrapping_add(s)), frequency),
+            Node::OpenSimplex{seed: s, frequency} =>
+                Graph::OpenSimplex(OpenSimplex::new().set_seed(seed.wrapping_add(s)), frequency),
+            Node::Worley{seed: s, frequency} =>
+                Graph::Worley(Worley::new().set_seed(seed.wrapping_add(s)), frequency),
+            Node::Value{seed: s, frequency} =>
+                Graph::Value(Value::new().set_seed(seed.wrapping_add(s)), frequency),
+            Node::Constant(v) => Graph::Constant(v),
+            Node::Add(ref a, ref b) => Graph::Add(build(a), build(b)),
+            Node::Mul(ref a, ref b) => Graph::Mul(build(a), build(b)),
+            Node::Min(ref a, ref b) => Graph::Min(build(a), build(b)),
+            Node::Max(ref a, ref b) => Graph::Max(build(a), build(b)),
+            Node::Select{ref control, ref a, ref b, threshold, falloff} =>
+                Graph::Select(build(control), build(a), build(b), threshold, falloff),
+            Node::Blend{ref control, ref a, ref b} =>
+                Graph::Blend(build(control), build(a), build(b)),
+            Node::Curve{ref source, ref points} => Graph::Curve(build(source), Curve::new(points)),
+            Node::Terrace{ref source, ref steps} => {
+                let mut steps = steps.clone();
+                steps.sort_by(|p, q| p.partial_cmp(q).unwrap_or(Ordering::Equal));
+                Graph::Terrace(build(source), steps)
+            },
+            Node::Clamp{ref source, min, max} => Graph::Clamp(build(source), min, max),
+            Node::Abs(ref source) => Graph::Abs(build(source)),
+            Node::ScaleBias{ref source, scale, bias} => Graph::ScaleBias(build(source), scale, bias),
+        }
+    }
+}
+
+// A recipe with its noise generators seeded and ready to sample
+#[derive(Debug,Clone)]
+pub enum Graph {
+    Perlin(Perlin,f64),
+    OpenSimplex(OpenSimplex,f64),
+    Worley(Worley,f64),
+    Value(Value,f64),
+    Constant(f64),
+    Add(Box<Graph>,Box<Graph>),
+    Mul(Box<Graph>,Box<Graph>),
+    Min(Box<Graph>,Box<Graph>),
+    Max(Box<Graph>,Box<Graph>),
+    Select(Box<Graph>,Box<Graph>,Box<Graph>,f64,f64),
+    Blend(Box<Graph>,Box<Graph>,Box<Graph>),
+    Curve(Box<Graph>,Curve),
+    Terrace(Box<Graph>,Vec<f64>),
+    Clamp(Box<Graph>,f64,f64),
+    Abs(Box<Graph>),
+    ScaleBias(Box<Graph>,f64,f64),
+}
+
+// Points the graph can be evaluated at, 2D for flat maps, 3D for planets
+// and 4D for wrapped maps
+trait Point: Copy {
+    fn scale(self, f: f64) -> Self;
+}
+
+impl Point for [f64;2] {
+    fn scale(self, f: f64) -> [f64;2]{
+        [self[0]*f, self[1]*f]
+    }
+}
+
+impl Point for [f64;3] {
+    fn scale(self, f: f64) -> [f64;3]{
+        [self[0]*f, self[1]*f, self[2]*f]
+    }
+}
+
+impl Point for [f64;4] {
+    fn scale(self, f: f64) -> [f64;4]{
+        [self[0]*f, self[1]*f, self[2]*f, self[3]*f]
+    }
+}
+
+impl Graph {
+    fn eval<P: Point>(&self, p: P) -> f64
+        where Perlin: NoiseFn<P>, OpenSimplex: NoiseFn<P>, Worley: NoiseFn<P>, Value: NoiseFn<P>{
+        match *self{
+            Graph::Perlin(ref n, f) => n.get(p.scale(f)),
+            Graph::OpenSimplex(ref n, f) => n.get(p.scale(f)),
+            Graph::Worley(ref n, f) => n.get(p.scale(f)),
+            Graph::Value(ref n, f) => n.get(p.scale(f)),
+            Graph::Constant(v) => v,
+            Graph::Add(ref a, ref b) => a.eval(p) + b.eval(p),
+            Graph::Mul(ref a, ref b) => a.eval(p) * b.eval(p),
+            Graph::Min(ref a, ref b) => a.eval(p).min(b.eval(p)),
+            Graph::Max(ref a, ref b) => a.eval(p).max(b.eval(p)),
+            Graph::Select(ref control, ref a, ref b, threshold, falloff) => {
+                let c = control.eval(p);
+                if falloff <= 0.0{
+                    if c < threshold {a.eval(p)} else {b.eval(p)}
+                } else if c < threshold - falloff{
+                    a.eval(p)
+                } else if c > threshold + falloff{
+                    b.eval(p)
+                } else {
+                    let t = (c - (threshold - falloff)) / (2.0*falloff);
+                    let t = t*t*(3.0 - 2.0*t);
+                    a.eval(p)*(1.0 - t) + b.eval(p)*t
+                }
+            },
+            Graph::Blend(ref control, ref a, ref b) => {
+                let t = (control.eval(p) + 1.0)*0.5;
+                a.eval(p)*(1.0 - t) + b.eval(p)*t
+            },
+            Graph::Curve(ref source, ref curve) => curve.at(source.eval(p)),
+            Graph::Terrace(ref source, ref steps) => terrace(source.eval(p), steps, 0.5),
+            Graph::Clamp(ref source, min, max) => source.eval(p).max(min).min(max),
+            Graph::Abs(ref source) => source.eval(p).abs(),
+            Graph::ScaleBias(ref source, scale, bias) => source.eval(p)*scale + bias,
+        }
+    }
+}
+
+impl NoiseFn<[f64;2]> for Graph {
+    fn get(&self, p: [f64;2]) -> f64{
+        self.eval(p)
+    }
+}
+
+impl NoiseFn<[f64;3]> for Graph {
+    fn get(&self, p: [f64;3]) -> f64{
+        self.eval(p)
+    }
+}
+
+impl NoiseFn<[f64;4]> for Graph {
+    fn get(&self, p: [f64;4]) -> f64{
+        self.eval(p)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn eval(recipe: &str) -> f64{
+        Node::from_ron(recipe).unwrap().build(1).get([0.3, 0.7])
+    }
+
+    #[test]
+    fn mesa_recipe_parses_and_evaluates(){
+        let graph = Node::from_ron(include_str!("../../recipes/mesa.ron")).unwrap().build(5);
+        match graph{
+            Graph::Select(_, _, _, threshold, falloff) => assert_eq!((threshold, falloff), (0.0, 0.15)),
+            ref other => panic!("expected a Select, got {:?}", other),
+        }
+        for i in 0..50{
+            let p = [i as f64*0.13, i as f64*0.07];
+            assert!(graph.get(p).is_finite());
+        }
+    }
+
+    #[test]
+    fn combinators(){
+        assert_eq!(eval("Constant(0.25)"), 0.25);
+        assert_eq!(eval("Add(Constant(1.0), Constant(2.0))"), 3.0);
+        assert_eq!(eval("Mul(Constant(1.5), Constant(2.0))"), 3.0);
+        assert_eq!(eval("Min(Constant(1.0), Constant(2.0))"), 1.0);
+        assert_eq!(eval("Max(Constant(1.0), Constant(2.0))"), 2.0);
+        assert_eq!(eval("Blend(control: Constant(0.0), a: Constant(1.0), b: Constant(3.0))"), 2.0);
+        assert_eq!(eval("Blend(control: Constant(-1.0), a: Constant(1.0), b: Constant(3.0))"), 1.0);
+    }
+
+    #[test]
+    fn select(){
+        // no falloff is a hard switch, b from the threshold up
+        assert_eq!(eval("Select(control: Constant(0.0), a: Constant(1.0), b: Constant(2.0))"), 2.0);
+        assert_eq!(eval("Select(control: Constant(-0.1), a: Constant(1.0), b: Constant(2.0))"), 1.0);
+        assert_eq!(eval("Select(control: Constant(-0.5), a: Constant(1.0), b: Constant(2.0), falloff: 0.2)"), 1.0);
+        assert_eq!(eval("Select(control: Constant(0.5), a: Constant(1.0), b: Constant(2.0), falloff: 0.2)"), 2.0);
+        assert_eq!(eval("Select(control: Constant(0.0), a: Constant(1.0), b: Constant(2.0), falloff: 0.2)"), 1.5);
+    }
+
+    #[test]
+    fn modifiers(){
+        let curve = |v: f64| eval(&format!("Curve(source: Constant({:?}), points: [(1.0, 0.0), (-1.0, -1.0), (0.0, 0.5)])", v));
+        assert_eq!(curve(-1.0), -1.0);
+        assert_eq!(curve(0.0), 0.5);
+        assert_eq!(curve(1.0), 0.0);
+        assert_eq!(curve(-2.0), -1.0);
+        assert_eq!(curve(2.0), 0.0);
+        // the cubic stays within the points either side
+        assert!(curve(-0.5) > -1.0 && curve(-0.5) < 0.5);
+        assert!(curve(0.5) > 0.0 && curve(0.5) < 0.5);
+        let terrace = |v: f64| eval(&format!("Terrace(source: Constant({:?}), steps: [0.5, -1.0, 0.0])", v));
+        assert_eq!(terrace(-2.0), -1.0);
+        assert_eq!(terrace(0.0), 0.0);
+        assert_eq!(terrace(0.5), 0.5);
+        assert_eq!(terrace(1.0), 0.5);
+        assert!(terrace(0.25) < 0.25 && terrace(0.25) > 0.0);
+        assert_eq!(eval("Clamp(source: Constant(3.0), min: -1.0, max: 1.0)"), 1.0);
+        assert_eq!(eval("Clamp(source: Constant(-3.0), min: -1.0, max: 1.0)"), -1.0);
+        assert_eq!(eval("Abs(Constant(-0.5))"), 0.5);
+        assert_eq!(eval("ScaleBias(source: Constant(2.0), scale: 0.5, bias: 1.0)"), 2.0);
+    }
+
+    #[test]
+    fn nan_points_do_not_panic(){
+        let nan = std::f64::NAN;
+        let source = || Box::new(Node::Constant(0.5));
+        Node::Curve{source: source(), points: vec![(0.0, 0.0), (nan, 1.0), (1.0, 2.0)]}.build(1).get([0.0, 0.0]);
+        Node::Terrace{source: source(), steps: vec![0.0, nan, 1.0]}.build(1).get([0.0, 0.0]);
+    }
+}
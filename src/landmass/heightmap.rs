@@ -0,0 +1,341 @@
+use image;
+use png::{self,HasParameters};
+use std::{error,fmt,io};
+use std::fs::File;
+use std::io::{Read,Write,BufWriter};
+use std::path::Path;
+
+use super::{MapParameters,WorldMap,Layers,model,normalize};
+
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum HeightFormat {
+    Png8,
+    Png16,
+    Raw16,
+    // portable float map, single channel little endian f32
+    Pfm,
+}
+
+#[derive(Debug)]
+pub enum MapError {
+    Io(io::Error),
+    Image(image::ImageError),
+    Format(String),
+}
+
+impl fmt::Display for MapError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result{
+        match *self{
+            MapError::Io(ref e) => write!(f, "io error: {}", e),
+            MapError::Image(ref e) => write!(f, "image error: {}", e),
+            MapError::Format(ref e) => write!(f, "format error: {}", e),
+        }
+    }
+}
+
+impl error::Error for MapError {
+    fn description(&self) -> &str{
+        match *self{
+            MapError::Io(_) => "io error",
+            MapError::Image(_) => "image error",
+            MapError::Format(ref e) => e,
+        }
+    }
+}
+
+impl From<io::Error> for MapError {
+    fn from(e: io::Error) -> MapError{
+        MapError::Io(e)
+    }
+}
+
+impl From<image::ImageError> for MapError {
+    fn from(e: image::ImageError) -> MapError{
+        MapError::Image(e)
+    }
+}
+
+impl From<png::DecodingError> for MapError {
+    fn from(e: png::DecodingError) -> MapError{
+        match e{
+            png::DecodingError::IoError(e) => MapError::Io(e),
+            e => MapError::Format(e.to_string()),
+        }
+    }
+}
+
+impl WorldMap {
+    // Loads a grayscale heightmap. PNGs may be 8 or 16 bit, files ending in
+    // .raw or .r16 are read as square headerless little endian 16 bit
+    // samples, anything else is handed to the image crate and converted to
+    // 8 bit luma. Black maps to 0 and white to vertical_scale. PFM files
+    // keep their heights and are only multiplied by vertical_scale.
+    pub fn from_image<P: AsRef<Path>>(path: P, vertical_scale: f32, horizontal_scale: f32) -> Result<WorldMap,MapError>{
+        let path = path.as_ref();
+        let extension = path.extension()
+            .and_then(|e| e.to_str())
+            .map(|e| e.to_lowercase())
+            .unwrap_or_default();
+        let (width, height, samples) = match extension.as_str(){
+            "raw" | "r16" => {
+                let bytes = read_bytes(path)?;
+                let side = ((bytes.len()/2) as f64).sqrt() as usize;
+                if side*side*2 != bytes.len(){
+                    return Err(MapError::Format(format!("{} bytes is not a square RAW16 heightmap", bytes.len())));
+                }
+                (side, side, raw16(&bytes))
+            },
+            "png" => read_png(path)?,
+            "pfm" => read_pfm(path)?,
+            _ => read_luma(path)?,
+        };
+        Ok(WorldMap::from_samples(width, height, &samples, vertical_scale, horizontal_scale))
+    }
+
+    pub fn from_raw<P: AsRef<Path>>(path: P, width: usize, height: usize, vertical_scale: f32, horizontal_scale: f32) -> Result<WorldMap,MapError>{
+        let bytes = read_bytes(path.as_ref())?;
+        if bytes.len() != width*height*2{
+            return Err(MapError::Format(format!("expected {} bytes for a {}x{} RAW16 heightmap, found {}",
+                                                width*height*2, width, height, bytes.len())));
+        }
+        Ok(WorldMap::from_samples(width, height, &raw16(&bytes), vertical_scale, horizontal_scale))
+    }
+
+    // Integer formats are stretched so the lowest point is black and the
+    // highest white, PFM stores the heights as they are.
+    pub fn export<P: AsRef<Path>>(&self, path: P, format: HeightFormat) -> Result<(),MapError>{
+        write_samples(path, self.parameters.width as u32, self.parameters.height as u32, &self.heights(), format)
+    }
+
+    // samples are row major, integer formats arrive in the range 0..1
+    fn from_samples(width: usize, height: usize, samples: &[f32], vertical_scale: f32, horizontal_scale: f32) -> WorldMap{
+        let params = MapParameters::new(width as i32, height as i32, horizontal_scale, 0, 0.0, 0.0);
+        let mut vertecies = Vec::with_capacity(width*height);
+        for i in 0..height{
+            for j in 0..width{
+                let x = j as f32 * horizontal_scale;
+                let z = i as f32 * horizontal_scale;
+                let y = samples[i*width + j] * vertical_scale;
+                vertecies.push(model::object::Vertex{position:(x,y,z)});
+            }
+        }
+        WorldMap{
+            parameters: params,
+            seed: 0,
+            vertecies,
+            layers: Layers::new(width, height),
+            apron: None,
+        }
+    }
+}
+
+// row major samples in any of the height formats
+pub fn write_samples<P: AsRef<Path>>(path: P, width: u32, height: u32, samples: &[f32], format: HeightFormat) -> Result<(),MapError>{
+    let mut file = BufWriter::new(File::create(path)?);
+    match format{
+        HeightFormat::Png8 => {
+            let bytes: Vec<u8> = normalize(samples).iter()
+                .map(|h| (h*255.0).round() as u8)
+                .collect();
+            image::png::PNGEncoder::new(&mut file).encode(&bytes, width, height, image::Gray(8))?;
+            file.flush()?;
+        },
+        HeightFormat::Png16 => {
+            let mut bytes = Vec::with_capacity(samples.len()*2);
+            for h in normalize(samples){
+                let v = (h*65535.0).round() as u16;
+                bytes.push((v >> 8) as u8);
+                bytes.push(v as u8);
+            }
+            image::png::PNGEncoder::new(&mut file).encode(&bytes, width, height, image::Gray(16))?;
+            file.flush()?;
+        },
+        HeightFormat::Raw16 => {
+            let mut bytes = Vec::with_capacity(samples.len()*2);
+            for h in normalize(samples){
+                let v = (h*65535.0).round() as u16;
+                bytes.push(v as u8);
+                bytes.push((v >> 8) as u8);
+            }
+            file.write_all(&bytes)?;
+            file.flush()?;
+        },
+        HeightFormat::Pfm => {
+            write!(file, "Pf\n{} {}\n-1.0\n", width, height)?;
+            // rows are stored bottom to top
+            for row in samples.chunks(width as usize).rev(){
+                for h in row{
+                    let bits = h.to_bits();
+                    file.write_all(&[bits as u8, (bits >> 8) as u8, (bits >> 16) as u8, (bits >> 24) as u8])?;
+                }
+            }
+            file.flush()?;
+        },
+    }
+    Ok(())
+}
+
+fn read_bytes(path: &Path) -> Result<Vec<u8>,MapError>{
+    let mut bytes = Vec::new();
+    File::open(path)?.read_to_end(&mut bytes)?;
+    Ok(bytes)
+}
+
+fn raw16(bytes: &[u8]) -> Vec<f32>{
+    bytes.chunks(2)
+        .map(|b| ((b[1] as u16) << 8 | b[0] as u16) as f32 / 65535.0)
+        .collect()
+}
+
+fn read_pfm(path: &Path) -> Result<(usize,usize,Vec<f32>),MapError>{
+    let bytes = read_bytes(path)?;
+    // header is four whitespace separated tokens followed by one whitespace byte
+    let mut tokens = Vec::new();
+    let mut start = 0;
+    let mut i = 0;
+    while tokens.len() < 4 && i < bytes.len(){
+        if (bytes[i] as char).is_whitespace(){
+            if i > start{
+                tokens.push(String::from_utf8_lossy(&bytes[start..i]).into_owned());
+            }
+            start = i + 1;
+        }
+        i += 1;
+    }
+    let header = |n: usize| tokens.get(n).cloned().unwrap_or_default();
+    if header(0) != "Pf"{
+        return Err(MapError::Format("expected a grayscale PFM".to_string()));
+    }
+    let width: usize = header(1).parse().map_err(|_| MapError::Format("bad PFM width".to_string()))?;
+    let height: usize = header(2).parse().map_err(|_| MapError::Format("bad PFM height".to_string()))?;
+    let endian: f32 = header(3).parse().map_err(|_| MapError::Format("bad PFM scale".to_string()))?;
+    if width == 0 || height == 0{
+        return Err(MapError::Format(format!("{}x{} is not a PFM heightmap", width, height)));
+    }
+    let size = width.checked_mul(height).and_then(|n| n.checked_mul(4))
+        .ok_or_else(|| MapError::Format(format!("{}x{} PFM is too large", width, height)))?;
+    let data = &bytes[start..];
+    if data.len() != size{
+        return Err(MapError::Format(format!("expected {} bytes of PFM data, found {}", size, data.len())));
+    }
+    let floats: Vec<f32> = data.chunks(4).map(|b|{
+        let bits = if endian < 0.0{
+            (b[3] as u32) << 24 | (b[2] as u32) << 16 | (b[1] as u32) << 8 | b[0] as u32
+        } else {
+            (b[0] as u32) << 24 | (b[1] as u32) << 16 | (b[2] as u32) << 8 | b[3] as u32
+        };
+        f32::from_bits(bits)
+    }).collect();
+    let mut samples = Vec::with_capacity(floats.len());
+    for row in floats.chunks(width).rev(){
+        samples.extend_from_slice(row);
+    }
+    Ok((width, height, samples))
+}
+
+fn read_luma(path: &Path) -> Result<(usize,usize,Vec<f32>),MapError>{
+    let img = image::open(path)?.to_luma();
+    let (width, height) = img.dimensions();
+    let samples = img.into_raw().into_iter().map(|v| v as f32/255.0).collect();
+    Ok((width as usize, height as usize, samples))
+}
+
+// The image crate strips 16 bit PNGs down to 8 bits, so decode them here.
+// Samples are read untransformed since the png crate's expansion does not
+// handle 16 bit grayscale.
+fn read_png(path: &Path) -> Result<(usize,usize,Vec<f32>),MapError>{
+    let mut decoder = png::Decoder::new(File::open(path)?);
+    decoder.set(png::Transformations::IDENTITY);
+    let (info, mut reader) = decoder.read_info()?;
+    let mut buffer = vec![0; info.buffer_size()];
+    reader.next_frame(&mut buffer)?;
+    let channels = match info.color_type{
+        png::ColorType::Grayscale => 1,
+        png::ColorType::GrayscaleAlpha => 2,
+        _ => return read_luma(path),
+    };
+    let bits = info.bit_depth as usize;
+    let max = ((1u32 << bits) - 1) as f32;
+    let width = info.width as usize;
+    let mut samples = Vec::with_capacity(width*info.height as usize);
+    for row in buffer.chunks(info.line_size){
+        for i in 0..width{
+            let bit = i*channels*bits;
+            let byte = bit/8;
+            let v = match bits{
+                16 => (row[byte] as u32) << 8 | row[byte + 1] as u32,
+                8 => row[byte] as u32,
+                _ => (row[byte] as u32 >> (8 - bits - bit%8)) & ((1 << bits) - 1),
+            };
+            samples.push(v as f32 / max);
+        }
+    }
+    Ok((width, info.height as usize, samples))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn temp(name: &str) -> PathBuf{
+        std::env::temp_dir().join(format!("landmass_{}_{}", std::process::id(), name))
+    }
+
+    fn round_trip<F: Fn(&Path) -> Result<WorldMap,MapError>>(name: &str, format: HeightFormat, read: F) -> (Vec<f32>,Vec<f32>){
+        let map = WorldMap::with_seed(MapParameters::new(23, 17, 1.0, 4, 0.5, 2.0), 5);
+        let path = temp(name);
+        map.export(&path, format).unwrap();
+        let back = read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!((back.parameters.width, back.parameters.height), (23, 17));
+        (map.heights(), back.heights())
+    }
+
+    fn within(expected: &[f32], found: &[f32], tolerance: f32){
+        for (e,f) in normalize(expected).iter().zip(found){
+            assert!((e - f).abs() <= tolerance, "expected {} found {}", e, f);
+        }
+    }
+
+    #[test]
+    fn png_round_trips(){
+        let (heights, back) = round_trip("8.png", HeightFormat::Png8, |p| WorldMap::from_image(p, 1.0, 1.0));
+        within(&heights, &back, 1.0/255.0);
+        let (heights, back) = round_trip("16.png", HeightFormat::Png16, |p| WorldMap::from_image(p, 1.0, 1.0));
+        within(&heights, &back, 1.0/65535.0);
+    }
+
+    #[test]
+    fn pfm_round_trips_exactly(){
+        let (heights, back) = round_trip("heights.pfm", HeightFormat::Pfm, |p| WorldMap::from_image(p, 1.0, 1.0));
+        assert_eq!(heights, back);
+    }
+
+    #[test]
+    fn raw16_round_trips(){
+        let (heights, back) = round_trip("heights.raw", HeightFormat::Raw16, |p| WorldMap::from_raw(p, 23, 17, 1.0, 1.0));
+        within(&heights, &back, 1.0/65535.0);
+    }
+
+    #[test]
+    fn malformed_pfm_headers_are_rejected(){
+        for (name, header) in [("empty.pfm", "Pf\n0 0\n-1.0\n"), ("huge.pfm", "Pf\n18446744073709551615 2\n-1.0\n")].iter(){
+            let path = temp(name);
+            std::fs::write(&path, header).unwrap();
+            let result = WorldMap::from_image(&path, 1.0, 1.0);
+            std::fs::remove_file(&path).unwrap();
+            match result{
+                Err(MapError::Format(_)) => (),
+                other => panic!("{} gave {:?}", name, other.map(|m| m.heights().len())),
+            }
+        }
+    }
+
+    #[test]
+    fn export_to_a_missing_directory_fails(){
+        let map = WorldMap::with_seed(MapParameters::new(4, 4, 1.0, 1, 0.5, 2.0), 5);
+        let path = temp("missing").join("heights.png");
+        assert!(map.export(&path, HeightFormat::Png8).is_err());
+    }
+}
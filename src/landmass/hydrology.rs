@@ -0,0 +1,355 @@
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::path::Path;
+
+use super::{WorldMap,MapError,Polyline,Layer,FLOW,model};
+use super::coast::write_geojson;
+
+const NEIGHBOURS: [(i32,i32);8] = [(-1,-1),(0,-1),(1,-1),(-1,0),(1,0),(-1,1),(0,1),(1,1)];
+
+// Min-heap entry for the priority flood, ties broken by index so the fill is
+// the same every run
+#[derive(Debug,Clone,Copy,PartialEq)]
+struct Cell(f32,usize);
+
+impl Eq for Cell {}
+
+impl Ord for Cell {
+    fn cmp(&self, other: &Cell) -> Ordering{
+        other.0.partial_cmp(&self.0).unwrap_or(Ordering::Equal)
+            .then_with(|| other.1.cmp(&self.1))
+    }
+}
+
+impl PartialOrd for Cell {
+    fn partial_cmp(&self, other: &Cell) -> Option<Ordering>{
+        Some(self.cmp(other))
+    }
+}
+
+// D8 flow over the depression filled surface
+#[derive(Debug,Clone)]
+pub struct Flow {
+    pub width: usize,
+    pub height: usize,
+    pub filled: Vec<f32>,
+    // the neighbour each cell drains into, None where water leaves the map
+    pub direction: Vec<Option<usize>>,
+    // number of cells draining through each cell, itself included
+    pub accumulation: Vec<f32>,
+}
+
+// A closed basin flooded up to the height where it would spill over
+#[derive(Debug,Clone)]
+pub struct Lake {
+    pub level: f32,
+    pub cells: Vec<usize>,
+    // the lowest cell on the shore, where the lake drains once full
+    pub outlet: usize,
+    pub area: f32,
+    pub volume: f32,
+    pub depth: f32,
+}
+
+// Priority flood from the border inwards. Every cell ends up at least
+// epsilon above the cell it was reached from, so no flats or pits are left.
+fn priority_flood(heights: &[f32], width: usize, height: usize, epsilon: f32) -> Vec<f32>{
+    let mut filled = heights.to_vec();
+    let mut closed = vec![false; heights.len()];
+    let mut open = BinaryHeap::new();
+    for i in 0..height{
+        for j in 0..width{
+            if i == 0 || j == 0 || i == height - 1 || j == width - 1{
+                let idx = i*width + j;
+                closed[idx] = true;
+                open.push(Cell(filled[idx], idx));
+            }
+        }
+    }
+    while let Some(Cell(h, idx)) = open.pop(){
+        for n in neighbours(idx, width, height){
+            if !closed[n]{
+                closed[n] = true;
+                filled[n] = filled[n].max(h + epsilon);
+                open.push(Cell(filled[n], n));
+            }
+        }
+    }
+    filled
+}
+
+fn neighbours(idx: usize, width: usize, height: usize) -> Vec<usize>{
+    let (i, j) = ((idx / width) as i32, (idx % width) as i32);
+    NEIGHBOURS.iter()
+        .map(|&(di,dj)| (i + di, j + dj))
+        .filter(|&(ni,nj)| ni >= 0 && nj >= 0 && ni < height as i32 && nj < width as i32)
+        .map(|(ni,nj)| ni as usize*width + nj as usize)
+        .collect()
+}
+
+impl Flow {
+    fn new(map: &WorldMap) -> Flow{
+        let width = map.parameters.width as usize;
+        let height = map.parameters.height as usize;
+        let filled = priority_flood(&map.heights(), width, height, 1e-3);
+        let mut direction = vec![None; filled.len()];
+        for idx in 0..filled.len(){
+            let (i, j) = (idx / width, idx % width);
+            let mut steepest = 0.0;
+            for n in neighbours(idx, width, height){
+                let diagonal = n / width != i && n % width != j;
+                let drop = (filled[idx] - filled[n]) / if diagonal {2f32.sqrt()} else {1.0};
+                if drop > steepest{
+                    steepest = drop;
+                    direction[idx] = Some(n);
+                }
+            }
+        }
+        let mut order: Vec<usize> = (0..filled.len()).collect();
+        order.sort_by(|&a, &b| filled[b].partial_cmp(&filled[a]).unwrap_or(Ordering::Equal));
+        let mut accumulation = vec![1.0f32; filled.len()];
+        for idx in order{
+            if let Some(n) = direction[idx]{
+                accumulation[n] += accumulation[idx];
+            }
+        }
+        Flow{width, height, filled, direction, accumulation}
+    }
+
+    // Cell paths for every river carrying at least threshold cells of flow.
+    // Each path starts at a source, highest first, and runs downstream until
+    // it leaves the map or reaches a river already traced, ending on the
+    // cell where the two join.
+    pub fn rivers(&self, threshold: f32) -> Vec<Vec<usize>>{
+        let river = |idx: usize| self.accumulation[idx] >= threshold;
+        let mut fed = vec![false; self.filled.len()];
+        for idx in 0..self.filled.len(){
+            if let Some(n) = self.direction[idx]{
+                if river(idx){
+                    fed[n] = true;
+                }
+            }
+        }
+        let mut visited = vec![false; self.filled.len()];
+        let mut paths = Vec::new();
+        let mut sources: Vec<usize> = (0..self.filled.len()).filter(|&idx| river(idx) && !fed[idx]).collect();
+        sources.sort_by(|&a, &b| self.filled[b].partial_cmp(&self.filled[a]).unwrap_or(Ordering::Equal));
+        for source in sources{
+            let mut path = vec![source];
+            visited[source] = true;
+            let mut at = source;
+            while let Some(n) = self.direction[at]{
+                path.push(n);
+                if visited[n]{
+                    break;
+                }
+                visited[n] = true;
+                at = n;
+            }
+            paths.push(path);
+        }
+        paths
+    }
+}
+
+impl WorldMap {
+    pub fn flow(&self) -> Flow{
+        Flow::new(self)
+    }
+
+    // Stores the flow accumulation in the flow layer
+    pub fn with_flow(&self) -> WorldMap{
+        let mut map = self.clone();
+        map.layers.set(FLOW, Layer::Scalar(self.flow().accumulation));
+        map
+    }
+
+    // Raises every closed basin to just above its spill level, leaving a
+    // slight slope towards the outlet so water can always run off
+    pub fn fill_depressions(&self) -> WorldMap{
+        let width = self.parameters.width as usize;
+        let height = self.parameters.height as usize;
+        self.with_heights(priority_flood(&self.heights(), width, height, 1e-3))
+    }
+
+    // Every closed basin in the map, found by flooding from the border with
+    // no gradient so each basin fills exactly to its spill level. Basins
+    // sharing a spill level and touching are one lake.
+    pub fn lakes(&self) -> Vec<Lake>{
+        let width = self.parameters.width as usize;
+        let height = self.parameters.height as usize;
+        let cell_area = self.parameters.scale*self.parameters.scale;
+        let heights = self.heights();
+        let filled = priority_flood(&heights, width, height, 0.0);
+        let mut seen = vec![false; heights.len()];
+        let mut lakes = Vec::new();
+        for start in 0..heights.len(){
+            if seen[start] || filled[start] <= heights[start]{
+                continue;
+            }
+            let level = filled[start];
+            seen[start] = true;
+            let mut cells = Vec::new();
+            let mut open = vec![start];
+            while let Some(idx) = open.pop(){
+                cells.push(idx);
+                for n in neighbours(idx, width, height){
+                    if !seen[n] && filled[n] > heights[n] && filled[n] == level{
+                        seen[n] = true;
+                        open.push(n);
+                    }
+                }
+            }
+            cells.sort();
+            let outlet = cells.iter()
+                .flat_map(|&idx| neighbours(idx, width, height))
+                .filter(|n| cells.binary_search(n).is_err())
+                .min_by(|&a, &b| heights[a].partial_cmp(&heights[b]).unwrap_or(Ordering::Equal).then(a.cmp(&b)))
+                .unwrap_or(start);
+            let volume = cells.iter().map(|&idx| level - heights[idx]).sum::<f32>()*cell_area;
+            let depth = cells.iter().map(|&idx| level - heights[idx]).fold(0.0, f32::max);
+            lakes.push(Lake{
+                level,
+                area: cells.len() as f32*cell_area,
+                cells,
+                outlet,
+                volume,
+                depth,
+            });
+        }
+        lakes
+    }
+
+    // Water surface height for cells under a lake
+    pub fn lake_levels(&self) -> Vec<Option<f32>>{
+        let mut levels = vec![None; self.vertecies.len()];
+        for lake in self.lakes(){
+            for idx in lake.cells{
+                levels[idx] = Some(lake.level);
+            }
+        }
+        levels
+    }
+
+    pub fn rivers(&self, threshold: f32) -> Vec<Polyline>{
+        self.flow().rivers(threshold).iter().map(|path|{
+            path.iter().map(|&idx|{
+                let (x,_,z) = self.vertecies[idx].position;
+                (x,z)
+            }).collect()
+        }).collect()
+    }
+
+    // Cuts river beds into the filled surface, deeper as more water passes.
+    // Beds only ever step down going downstream.
+    pub fn carve_rivers(&self, threshold: f32, depth: f32) -> WorldMap{
+        let flow = self.flow();
+        let heights = self.heights().iter().enumerate().map(|(idx,&h)|{
+            let a = flow.accumulation[idx];
+            if a >= threshold{
+                h.min(flow.filled[idx] - depth*(1.0 + (a/threshold).ln()))
+            } else {
+                h
+            }
+        }).collect();
+        self.with_heights(heights)
+    }
+
+    pub fn export_rivers_geojson<P: AsRef<Path>>(&self, path: P, threshold: f32) -> Result<(),MapError>{
+        write_geojson(path, &self.rivers(threshold))
+    }
+
+    // Flat ribbons width wide laid just above the terrain along each river
+    pub fn river_model(&self, threshold: f32, width: f32) -> model::object::Model{
+        let up = model::object::Normal{normal:(0.0,1.0,0.0)};
+        let mut vertices = Vec::new();
+        let mut ind = Vec::new();
+        for path in self.flow().rivers(threshold){
+            for pair in path.windows(2){
+                let (x0,y0,z0) = self.vertecies[pair[0]].position;
+                let (x1,y1,z1) = self.vertecies[pair[1]].position;
+                let (dx,dz) = (x1 - x0, z1 - z0);
+                let len = (dx*dx + dz*dz).sqrt();
+                if len == 0.0{
+                    continue;
+                }
+                let (px,pz) = (-dz/len*width*0.5, dx/len*width*0.5);
+                let base = vertices.len() as u32;
+                for &(x,y,z) in [(x0 + px,y0,z0 + pz),(x1 + px,y1,z1 + pz),
+                                 (x1 - px,y1,z1 - pz),(x0 - px,y0,z0 - pz)].iter(){
+                    vertices.push(model::object::Vertex{position:(x,y + 0.05,z)});
+                }
+                // same winding as the terrain mesh so both survive culling
+                ind.extend_from_slice(&[base, base + 2, base + 1, base, base + 3, base + 2]);
+            }
+        }
+        model::object::Model{
+            normals: Some(vec![up; vertices.len()]),
+            vertices,
+            colors: None,
+            index: Some(ind),
+            material: None,
+            bones: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::MapParameters;
+
+    fn map(width: i32, height: i32, scale: f32, heights: Vec<f32>) -> WorldMap{
+        WorldMap::with_seed(MapParameters::new(width, height, scale, 1, 0.5, 2.0), 1).with_heights(heights)
+    }
+
+    // 5x5 rim at 2 with a notch at 1 on the top edge around a 3x3 pit at 0
+    fn bowl() -> WorldMap{
+        let heights = (0..25).map(|idx|{
+            let (i, j) = (idx / 5, idx % 5);
+            if idx == 2 {1.0} else if i == 0 || j == 0 || i == 4 || j == 4 {2.0} else {0.0}
+        }).collect();
+        map(5, 5, 2.0, heights)
+    }
+
+    #[test]
+    fn bowl_fills_to_its_spill_level(){
+        let lakes = bowl().lakes();
+        assert_eq!(lakes.len(), 1);
+        let lake = &lakes[0];
+        assert_eq!(lake.level, 1.0);
+        assert_eq!(lake.cells, vec![6, 7, 8, 11, 12, 13, 16, 17, 18]);
+        assert_eq!(lake.outlet, 2);
+        assert_eq!(lake.area, 36.0);
+        assert_eq!(lake.volume, 36.0);
+        assert_eq!(lake.depth, 1.0);
+        let levels = bowl().lake_levels();
+        assert_eq!(levels[12], Some(1.0));
+        assert_eq!(levels[0], None);
+    }
+
+    #[test]
+    fn bowl_drains_through_the_notch(){
+        let map = bowl();
+        let filled = map.fill_depressions().heights();
+        for &idx in [6, 7, 8, 11, 12, 13, 16, 17, 18].iter(){
+            assert!(filled[idx] > 1.0);
+        }
+        let flow = map.flow();
+        assert_eq!(flow.direction[7], Some(2));
+        assert_eq!(flow.direction[2], None);
+        // the pit and the two rim cells either side of the notch
+        assert!(flow.accumulation[2] >= 12.0);
+    }
+
+    #[test]
+    fn tilted_plane_accumulates_down_the_slope(){
+        let (w, h) = (6, 4);
+        let flow = map(w as i32, h as i32, 1.0, (0..w*h).map(|idx| (idx % w) as f32).collect()).flow();
+        for idx in 0..w*h{
+            let j = idx % w;
+            assert_eq!(flow.accumulation[idx], (w - j) as f32);
+            assert_eq!(flow.direction[idx], if j == 0 {None} else {Some(idx - 1)});
+        }
+    }
+}
@@ -0,0 +1,301 @@
+use image;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{Write,BufWriter};
+use std::path::Path;
+
+use super::{WorldMap,MapError,HeightFormat,model};
+use super::heightmap::write_samples;
+
+// Names the built in passes read and write. The heights are not stored
+// among the layers, they live in the map's vertices, but HEIGHT reaches
+// them through with_layer and export_layer.
+pub const HEIGHT: &str = "height";
+pub const TEMPERATURE: &str = "temperature";
+pub const MOISTURE: &str = "moisture";
+pub const SEDIMENT: &str = "sediment";
+pub const FLOW: &str = "flow";
+pub const BIOME: &str = "biome";
+pub const MATERIAL: &str = "material";
+pub const SLOPE: &str = "slope";
+pub const ASPECT: &str = "aspect";
+pub const PROFILE_CURVATURE: &str = "profile_curvature";
+pub const PLAN_CURVATURE: &str = "plan_curvature";
+pub const PLATE: &str = "plate";
+pub const UPLIFT: &str = "uplift";
+
+#[derive(Debug,Clone)]
+pub enum Layer {
+    Scalar(Vec<f32>),
+    // categorical values, resampled by nearest cell instead of blended
+    Category8(Vec<u8>),
+    Category16(Vec<u16>),
+}
+
+impl Layer {
+    pub fn len(&self) -> usize{
+        match *self{
+            Layer::Scalar(ref v) => v.len(),
+            Layer::Category8(ref v) => v.len(),
+            Layer::Category16(ref v) => v.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool{
+        self.len() == 0
+    }
+}
+
+// Named grids laid out row major like the map's vertices, so one index
+// addresses the same cell in the heights and in every layer
+#[derive(Debug,Clone)]
+pub struct Layers {
+    width: usize,
+    height: usize,
+    layers: BTreeMap<String,Layer>,
+}
+
+impl Layers {
+    pub fn new(width: usize, height: usize) -> Layers{
+        Layers{width, height, layers: BTreeMap::new()}
+    }
+
+    pub fn width(&self) -> usize{
+        self.width
+    }
+
+    pub fn height(&self) -> usize{
+        self.height
+    }
+
+    pub fn index(&self, row: usize, col: usize) -> usize{
+        row*self.width + col
+    }
+
+    pub fn names(&self) -> Vec<&str>{
+        self.layers.keys().map(|k| k.as_str()).collect()
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Layer>{
+        self.layers.get(name)
+    }
+
+    pub fn scalar(&self, name: &str) -> Option<&[f32]>{
+        match self.layers.get(name){
+            Some(Layer::Scalar(v)) => Some(v),
+            _ => None,
+        }
+    }
+
+    pub fn category8(&self, name: &str) -> Option<&[u8]>{
+        match self.layers.get(name){
+            Some(Layer::Category8(v)) => Some(v),
+            _ => None,
+        }
+    }
+
+    pub fn category16(&self, name: &str) -> Option<&[u16]>{
+        match self.layers.get(name){
+            Some(Layer::Category16(v)) => Some(v),
+            _ => None,
+        }
+    }
+
+    // Replaces any layer already stored under name, a layer with the wrong
+    // number of cells is refused
+    pub fn insert(&mut self, name: &str, layer: Layer) -> Result<(),MapError>{
+        if layer.len() != self.width*self.height{
+            return Err(MapError::Format(format!("layer {} has {} cells, the {}x{} map needs {}",
+                                                name, layer.len(), self.width, self.height, self.width*self.height)));
+        }
+        self.set(name, layer);
+        Ok(())
+    }
+
+    // insert for the built in passes, whose layers are worked out from the
+    // map itself and always fit it
+    pub(crate) fn set(&mut self, name: &str, layer: Layer){
+        debug_assert_eq!(layer.len(), self.width*self.height);
+        self.layers.insert(name.to_string(), layer);
+    }
+
+    pub fn remove(&mut self, name: &str) -> Option<Layer>{
+        self.layers.remove(name)
+    }
+
+    // Every layer stretched over a width x height grid covering the same
+    // area. Scalars are interpolated bilinearly, categories take the
+    // nearest cell.
+    pub fn resample(&self, width: usize, height: usize) -> Layers{
+        let mut layers = Layers::new(width, height);
+        for (name, layer) in self.layers.iter(){
+            let layer = match *layer{
+                Layer::Scalar(ref v) => Layer::Scalar(bilinear(v, self.width, self.height, width, height)),
+                Layer::Category8(ref v) => Layer::Category8(nearest(v, self.width, self.height, width, height)),
+                Layer::Category16(ref v) => Layer::Category16(nearest(v, self.width, self.height, width, height)),
+            };
+            layers.layers.insert(name.clone(), layer);
+        }
+        layers
+    }
+
+    // The width x height block of every layer starting at (row,col)
+    pub fn crop(&self, row: usize, col: usize, width: usize, height: usize) -> Layers{
+        let mut layers = Layers::new(width, height);
+        for (name, layer) in self.layers.iter(){
+            let layer = match *layer{
+                Layer::Scalar(ref v) => Layer::Scalar(window(v, self.width, row, col, width, height)),
+                Layer::Category8(ref v) => Layer::Category8(window(v, self.width, row, col, width, height)),
+                Layer::Category16(ref v) => Layer::Category16(window(v, self.width, row, col, width, height)),
+            };
+            layers.layers.insert(name.clone(), layer);
+        }
+        layers
+    }
+}
+
+// the width x height block of a row major grid w wide starting at (row,col)
+fn window<T: Copy>(v: &[T], w: usize, row: usize, col: usize, width: usize, height: usize) -> Vec<T>{
+    (row..row + height).flat_map(|r| v[r*w + col..r*w + col + width].iter().cloned()).collect()
+}
+
+// position of cell n of to in the cells of from, both spanning the same length
+fn source_position(n: usize, from: usize, to: usize) -> f32{
+    if to > 1 {n as f32*(from - 1) as f32/(to - 1) as f32} else {0.0}
+}
+
+fn bilinear(v: &[f32], w: usize, h: usize, width: usize, height: usize) -> Vec<f32>{
+    let mut out = Vec::with_capacity(width*height);
+    for i in 0..height{
+        let y = source_position(i, h, height);
+        let r = (y.floor() as usize).min(h.saturating_sub(2));
+        let t = y - r as f32;
+        for j in 0..width{
+            let x = source_position(j, w, width);
+            let c = (x.floor() as usize).min(w.saturating_sub(2));
+            let s = x - c as f32;
+            let at = |r: usize, c: usize| v[r.min(h - 1)*w + c.min(w - 1)];
+            let top = at(r,c)*(1.0 - s) + at(r,c + 1)*s;
+            let bottom = at(r + 1,c)*(1.0 - s) + at(r + 1,c + 1)*s;
+            out.push(top*(1.0 - t) + bottom*t);
+        }
+    }
+    out
+}
+
+fn nearest<T: Copy>(v: &[T], w: usize, h: usize, width: usize, height: usize) -> Vec<T>{
+    let mut out = Vec::with_capacity(width*height);
+    for i in 0..height{
+        let r = (source_position(i, h, height).round() as usize).min(h - 1);
+        for j in 0..width{
+            let c = (source_position(j, w, width).round() as usize).min(w - 1);
+            out.push(v[r*w + c]);
+        }
+    }
+    out
+}
+
+impl WorldMap {
+    // A copy of the map with the layer stored under name. A scalar layer
+    // named HEIGHT replaces the heights instead.
+    pub fn with_layer(&self, name: &str, layer: Layer) -> Result<WorldMap,MapError>{
+        match (name, layer){
+            (HEIGHT, Layer::Scalar(heights)) => {
+                if heights.len() != self.vertecies.len(){
+                    return Err(MapError::Format(format!("{} heights do not fit the {} vertices of the map",
+                                                        heights.len(), self.vertecies.len())));
+                }
+                Ok(self.with_heights(heights))
+            },
+            (HEIGHT, _) => Err(MapError::Format("heights must be a scalar layer".to_string())),
+            (name, layer) => {
+                let mut map = self.clone();
+                map.layers.insert(name, layer)?;
+                Ok(map)
+            }
+        }
+    }
+
+    // The width x height window of the map starting at (row,col), heights
+    // and layers cut out together. Where the map reaches a cell past every
+    // edge of the window those cells become its apron.
+    pub fn crop(&self, row: usize, col: usize, width: usize, height: usize) -> WorldMap{
+        let w = self.parameters.width as usize;
+        let h = self.parameters.height as usize;
+        let mut params = self.parameters;
+        params.origin = (params.origin.0 + col as i32, params.origin.1 + row as i32);
+        params.width = width as i32;
+        params.height = height as i32;
+        let apron = if row > 0 && col > 0 && row + height < h && col + width < w{
+            Some(window(&self.heights(), w, row - 1, col - 1, width + 2, height + 2))
+        } else {
+            None
+        };
+        WorldMap{
+            parameters: params,
+            seed: self.seed,
+            vertecies: window(&self.vertecies, w, row, col, width, height),
+            layers: self.layers.crop(row, col, width, height),
+            apron,
+        }
+    }
+
+    // The same area on a width x height grid, heights and layers resampled
+    // together. Only meaningful for whole maps, not chunks of a larger one.
+    pub fn resample(&self, width: usize, height: usize) -> WorldMap{
+        let (w, h) = (self.parameters.width as usize, self.parameters.height as usize);
+        let heights = bilinear(&self.heights(), w, h, width, height);
+        let (x0,_,z0) = self.vertecies[0].position;
+        let (x1,_,z1) = self.vertecies[self.vertecies.len() - 1].position;
+        let step = |a: f32, b: f32, n: usize| if n > 1 {(b - a)/(n - 1) as f32} else {0.0};
+        let (sx, sz) = (step(x0, x1, width), step(z0, z1, height));
+        let mut vertecies = Vec::with_capacity(width*height);
+        for i in 0..height{
+            for j in 0..width{
+                let y = heights[i*width + j];
+                vertecies.push(model::object::Vertex{position:(x0 + j as f32*sx, y, z0 + i as f32*sz)});
+            }
+        }
+        let mut params = self.parameters;
+        params.width = width as i32;
+        params.height = height as i32;
+        if width > 1{
+            params.scale = sx;
+        }
+        WorldMap{
+            parameters: params,
+            seed: self.seed,
+            vertecies,
+            layers: self.layers.resample(width, height),
+            apron: None,
+        }
+    }
+
+    // Scalar layers, and HEIGHT, are written like heights in the given
+    // format. Categorical layers keep their raw values in an 8 or 16 bit
+    // grayscale PNG whatever the format.
+    pub fn export_layer<P: AsRef<Path>>(&self, name: &str, path: P, format: HeightFormat) -> Result<(),MapError>{
+        let width = self.parameters.width as u32;
+        let height = self.parameters.height as u32;
+        if name == HEIGHT{
+            return self.export(path, format);
+        }
+        match self.layers.get(name){
+            Some(Layer::Scalar(v)) => write_samples(path, width, height, v, format),
+            Some(Layer::Category8(v)) => {
+                let mut file = BufWriter::new(File::create(path)?);
+                image::png::PNGEncoder::new(&mut file).encode(v, width, height, image::Gray(8))?;
+                file.flush()?;
+                Ok(())
+            },
+            Some(Layer::Category16(v)) => {
+                let bytes: Vec<u8> = v.iter().flat_map(|&c| vec![(c >> 8) as u8, c as u8]).collect();
+                let mut file = BufWriter::new(File::create(path)?);
+                image::png::PNGEncoder::new(&mut file).encode(&bytes, width, height, image::Gray(16))?;
+                file.flush()?;
+                Ok(())
+            },
+            None => Err(MapError::Format(format!("map has no {} layer", name))),
+        }
+    }
+}
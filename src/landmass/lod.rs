@@ -0,0 +1,64 @@
+// Geomipmapping for square patches with side 2^k+1 vertices. Level n keeps
+// every 2^n'th vertex. Along an edge shared with a coarser neighbour the edge
+// vertices are snapped onto the neighbour's vertices, so both patches trace
+// the same edge and no cracks open between them.
+
+// (level, [north, east, south, west] neighbour levels)
+pub type LodKey = (u32,[u32;4]);
+
+#[derive(Debug,Clone,Copy)]
+pub struct Lod {
+    pub side: i32,
+    pub levels: u32,
+    distance: f32,
+}
+
+impl Lod {
+    // distance is how far from the camera level 0 is kept, each level after
+    // that covers twice the distance of the one before
+    pub fn new(side: i32, distance: f32) -> Lod{
+        let levels = (side - 1).trailing_zeros() + 1;
+        Lod{side, levels, distance}
+    }
+
+    pub fn level(&self, distance: f32) -> u32{
+        if distance <= self.distance{
+            return 0;
+        }
+        let level = (distance / self.distance).log2().floor() as u32 + 1;
+        level.min(self.levels - 1)
+    }
+
+    pub fn indices(&self, key: LodKey) -> Vec<u32>{
+        let (level, neighbours) = key;
+        let side = self.side;
+        let last = side - 1;
+        let step = 1 << level;
+        let snaps: Vec<i32> = neighbours.iter()
+            .map(|&n| if n > level {1 << n.min(self.levels - 1)} else {1})
+            .collect();
+        let index = |r: i32, c: i32| -> u32{
+            let (mut r, mut c) = (r, c);
+            if r == 0 {c -= c % snaps[0];}
+            if c == last {r -= r % snaps[1];}
+            if r == last {c -= c % snaps[2];}
+            if c == 0 {r -= r % snaps[3];}
+            (r*side + c) as u32
+        };
+        let cells = (last / step) as usize;
+        let mut ind = Vec::with_capacity(cells*cells*6);
+        for i in 0..(last / step){
+            for j in 0..(last / step){
+                let (r, c) = (i*step, j*step);
+                let tris = [[index(r, c + step), index(r + step, c + step), index(r, c)],
+                            [index(r, c), index(r + step, c + step), index(r + step, c)]];
+                for t in tris.iter(){
+                    if t[0] != t[1] && t[1] != t[2] && t[0] != t[2]{
+                        ind.extend_from_slice(t);
+                    }
+                }
+            }
+        }
+        ind
+    }
+}
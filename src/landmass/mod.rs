@@ -1,12 +1,77 @@
-use rand;
-use noise::{Perlin,Seedable,NoiseFn};
-use image::{self,ImageBuffer};
-use std::path::Path;
-
+use rand::{self,XorShiftRng,SeedableRng};
+use rayon::prelude::*;
+use noise::NoiseFn;
 use std;
+use std::collections::{HashMap,HashSet};
+use std::collections::hash_map::Entry;
+use std::hash::Hash;
 
 #[path = "../model/mod.rs"]
 mod model;
+mod source;
+mod erosion;
+mod chunk;
+mod lod;
+mod heightmap;
+mod graph;
+mod coast;
+mod hydrology;
+mod biome;
+mod layer;
+mod analysis;
+mod planet;
+mod volume;
+mod shaping;
+mod tectonics;
+mod crater;
+pub mod bench;
+
+pub use self::source::{NoiseType,Source};
+pub use self::erosion::{HydraulicParameters,ThermalParameters};
+pub use self::chunk::{ChunkKey,Chunks};
+pub use self::lod::{Lod,LodKey};
+pub use self::heightmap::{HeightFormat,MapError};
+pub use self::graph::{Node,Graph};
+pub use self::coast::{Falloff,Polyline};
+pub use self::hydrology::{Flow,Lake};
+pub use self::planet::{Planet,PlanetParameters,Face};
+pub use self::volume::{CaveParameters,Volume,VolumeChunks,VolumeKey};
+pub use self::biome::{Biome,ClimateParameters};
+pub use self::tectonics::PlateParameters;
+pub use self::crater::CraterParameters;
+pub use self::layer::{Layer,Layers,HEIGHT,TEMPERATURE,MOISTURE,SEDIMENT,FLOW,BIOME,MATERIAL,
+                      SLOPE,ASPECT,PROFILE_CURVATURE,PLAN_CURVATURE,PLATE,UPLIFT};
+pub use self::model::object::Color;
+
+// Domain warp, the sample point is pushed around by fBm of the same source
+// before the height is read. Each extra level of depth warps the original
+// point by noise taken at the previously warped point.
+#[derive(Debug,Clone,Copy)]
+pub struct Warp {
+    pub strength: f64,
+    pub frequency: f64,
+    pub levels: i32,
+    pub depth: u32,
+}
+
+impl Warp {
+    pub fn new(strength: f64, depth: u32) -> Warp{
+        Warp{strength, frequency: 1.0, levels: 4, depth}
+    }
+}
+
+// How the map closes on itself. A wrapped axis repeats every width - 1
+// (or height - 1) cells, so the first and last row or column of a map
+// sample the same heights. Wrapped maps read the source in 4D, laying the
+// plane out on a cylinder or a torus.
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum Wrap {
+    None,
+    // wraps along x only
+    Cylinder,
+    // wraps along x and z
+    Torus,
+}
 
 #[derive(Debug,Clone,Copy)]
 pub struct MapParameters {
@@ -16,128 +81,441 @@ pub struct MapParameters {
     levels: i32,
     scale_ratio: f32,
     freq_ratio: f32,
+    seed: Option<u32>,
+    noise: NoiseType,
+    origin: (i32,i32),
+    extent: (f32,f32),
+    warp: Option<Warp>,
+    rotation: f32,
+    offset: (f32,f32),
+    sea_level: f32,
+    parallel: bool,
+    wrap: Wrap,
 }
 
 impl MapParameters {
     pub fn new(width: i32, height: i32, scale: f32, levels: i32, scale_ratio: f32, freq_ratio: f32) -> MapParameters{
-        MapParameters{width,height,scale,levels,scale_ratio,freq_ratio}
+        MapParameters{width,height,scale,levels,scale_ratio,freq_ratio,
+                      seed:None,
+                      noise:NoiseType::Perlin,
+                      origin:(0,0),
+                      extent:(width as f32,height as f32),
+                      warp:None,
+                      rotation:0.0,
+                      offset:(0.0,0.0),
+                      sea_level:0.0,
+                      parallel:true,
+                      wrap:Wrap::None}
+    }
+
+    // A width x height window onto the same noise field, starting at origin
+    // (in grid cells). Windows that share an edge sample identical heights
+    // along it.
+    pub fn region(&self, origin: (i32,i32), width: i32, height: i32) -> MapParameters{
+        let mut params = *self;
+        params.origin = origin;
+        params.width = width;
+        params.height = height;
+        params
+    }
+
+    pub fn seeded(mut self, seed: u32) -> MapParameters{
+        self.seed = Some(seed);
+        self
+    }
+
+    // the seed set with seeded, or a fresh random one
+    fn seed_or_random(&self) -> u32{
+        match self.seed{
+            Some(seed) => seed,
+            None => rand::random()
+        }
+    }
+
+    pub fn with_noise(mut self, noise: NoiseType) -> MapParameters{
+        self.noise = noise;
+        self
+    }
+
+    pub fn with_sea_level(mut self, sea_level: f32) -> MapParameters{
+        self.sea_level = sea_level;
+        self
+    }
+
+    // Generation and meshing run across all cores unless this is turned
+    // off. Both paths give the same maps.
+    pub fn with_parallel(mut self, parallel: bool) -> MapParameters{
+        self.parallel = parallel;
+        self
+    }
+
+    pub fn with_wrap(mut self, wrap: Wrap) -> MapParameters{
+        self.wrap = wrap;
+        self
+    }
+
+    pub fn with_warp(mut self, warp: Warp) -> MapParameters{
+        self.warp = Some(warp);
+        self
+    }
+
+    // Each octave is rotated by a further rotation degrees and shifted by a
+    // further offset, which stops the octaves lining up along the lattice.
+    pub fn with_octave_transform(mut self, rotation: f32, offset: (f32,f32)) -> MapParameters{
+        self.rotation = rotation;
+        self.offset = offset;
+        self
     }
 
-    pub fn xy(&self,perlin: &Perlin, x: &f64,y: &f64) -> f64{
+    pub fn xy<T: Sample>(&self,source: &T, x: &f64,y: &f64) -> f64{
+        if self.wrap != Wrap::None{
+            return self.wrapped(source, *x, *y);
+        }
+        let xp = *x / (self.extent.0 as f64);
+        let yp = *y / (self.extent.1 as f64);
+        let mut p = [xp,yp];
+        if let Some(warp) = self.warp{
+            let mut w = p;
+            for d in 0..warp.depth{
+                let shift = 3.7*d as f64;
+                let qx = self.fbm(source, [w[0]*warp.frequency + shift, w[1]*warp.frequency + shift], warp.levels);
+                let qy = self.fbm(source, [w[0]*warp.frequency + 5.2 + shift, w[1]*warp.frequency + 1.3 + shift], warp.levels);
+                w = [xp + warp.strength*qx, yp + warp.strength*qy];
+            }
+            p = w;
+        }
+        self.fbm(source, p, self.levels)
+    }
+
+    fn fbm<T: Sample>(&self,source: &T, p: [f64;2], levels: i32) -> f64{
         let mut z:f64 = 0.0;
-        let xp = *x / (self.width as f64);
-        let yp = *y / (self.height as f64);
-        for i in 0..self.levels{
+        let (sin,cos) = (self.rotation as f64).to_radians().sin_cos();
+        let (mut xp,mut yp) = (p[0],p[1]);
+        for i in 0..levels{
             let f : f64 = self.freq_ratio.powi(i) as f64;  // lac^i
             let a : f64 = self.scale_ratio.powi(i) as f64; // per^i
-            z+=a*perlin.get([xp*f,yp*f]) ;            
+            let ox = self.offset.0 as f64 * i as f64;
+            let oy = self.offset.1 as f64 * i as f64;
+            z+=a*source.get([xp*f + ox,yp*f + oy]) ;
+            let (rx,ry) = (xp*cos - yp*sin, xp*sin + yp*cos);
+            xp = rx;
+            yp = ry;
+        }
+        z
+    }
+
+    // Same as xy, but positions are turned into fractions of the wrap
+    // period and looked up on circles whose circumference matches the
+    // period, so features keep roughly the size they have unwrapped.
+    fn wrapped<T: Sample>(&self, source: &T, x: f64, y: f64) -> f64{
+        let period_x = ((self.extent.0 - 1.0).max(1.0) * self.scale) as f64;
+        let period_y = ((self.extent.1 - 1.0).max(1.0) * self.scale) as f64;
+        // period in the normalised units the unwrapped noise is read in
+        let px = period_x / self.extent.0 as f64;
+        let py = period_y / self.extent.1 as f64;
+        let (u,v) = (x / period_x, y / period_y);
+        let point = |u: f64, v: f64| -> [f64;4]{
+            let tau = 2.0*std::f64::consts::PI;
+            let (su,cu) = (tau*(u - u.floor())).sin_cos();
+            let (rx,ry) = (px/tau, py/tau);
+            match self.wrap{
+                Wrap::Torus => {
+                    let (sv,cv) = (tau*(v - v.floor())).sin_cos();
+                    [rx*cu, rx*su, ry*cv, ry*sv]
+                },
+                _ => [rx*cu, rx*su, v*py, 0.0],
+            }
+        };
+        let mut w = (u,v);
+        if let Some(warp) = self.warp{
+            for d in 0..warp.depth{
+                let shift = 3.7*d as f64;
+                let q = point(w.0, w.1);
+                let at = |a: f64, b: f64| [q[0]*warp.frequency + a, q[1]*warp.frequency + a,
+                                           q[2]*warp.frequency + b, q[3]*warp.frequency + b];
+                let qx = self.fbm4(source, at(shift, shift), warp.levels);
+                let qy = self.fbm4(source, at(5.2 + shift, 1.3 + shift), warp.levels);
+                w = (u + warp.strength*qx/px, v + warp.strength*qy/py);
+            }
+        }
+        self.fbm4(source, point(w.0, w.1), self.levels)
+    }
+
+    // fBm at a point in space, with the octave rotation turning about y
+    pub fn xyz<T: Sample>(&self, source: &T, p: [f64;3]) -> f64{
+        let mut z:f64 = 0.0;
+        let (sin,cos) = (self.rotation as f64).to_radians().sin_cos();
+        let mut q = p;
+        for i in 0..self.levels{
+            let f : f64 = self.freq_ratio.powi(i) as f64;
+            let a : f64 = self.scale_ratio.powi(i) as f64;
+            let ox = self.offset.0 as f64 * i as f64;
+            let oy = self.offset.1 as f64 * i as f64;
+            z+=a*source.get([q[0]*f + ox, q[1]*f, q[2]*f + oy]);
+            q = [q[0]*cos - q[2]*sin, q[1], q[0]*sin + q[2]*cos];
+        }
+        z
+    }
+
+    fn fbm4<T: Sample>(&self, source: &T, p: [f64;4], levels: i32) -> f64{
+        let mut z:f64 = 0.0;
+        let (sin,cos) = (self.rotation as f64).to_radians().sin_cos();
+        let mut q = p;
+        for i in 0..levels{
+            let f : f64 = self.freq_ratio.powi(i) as f64;
+            let a : f64 = self.scale_ratio.powi(i) as f64;
+            let ox = self.offset.0 as f64 * i as f64;
+            let oy = self.offset.1 as f64 * i as f64;
+            z+=a*source.get([q[0]*f + ox, q[1]*f + ox, q[2]*f + oy, q[3]*f + oy]);
+            q = [q[0]*cos - q[2]*sin, q[1]*cos - q[3]*sin, q[0]*sin + q[2]*cos, q[1]*sin + q[3]*cos];
         }
         z
     }
 }
 
-#[derive(Debug)]
+// A height source that can be read on the plane, in 3D for planets and in
+// 4D for wrapped maps
+pub trait Sample: NoiseFn<[f64;2]> + NoiseFn<[f64;3]> + NoiseFn<[f64;4]> {}
+
+impl<T: NoiseFn<[f64;2]> + NoiseFn<[f64;3]> + NoiseFn<[f64;4]>> Sample for T {}
+
+#[derive(Debug,Clone)]
 pub struct WorldMap{
     pub parameters: MapParameters,
     pub seed: u32,
-    pub vertecies: Vec<model::object::Vertex>
+    pub vertecies: Vec<model::object::Vertex>,
+    pub layers: Layers,
+    // heights of a (width+2)x(height+2) window with the map in the middle.
+    // When present, smooth normals on the border take in the faces just
+    // outside the map, so windows that share an edge shade it the same.
+    pub apron: Option<Vec<f32>>,
 }
 
 impl WorldMap{
     pub fn new(params: MapParameters) -> WorldMap{
-        let seed = rand::random();
-        let perlin = Perlin::new();
-        let perlin = perlin.set_seed(seed);
-        let mut vertecies = Vec::with_capacity((params.width * params.height) as usize);
-        for i in 0..params.width{
-            for j in 0..params.height{
-                let x: f32 = (j as f32) * params.scale;
-                let z: f32 = (i as f32) * params.scale;
-                let y: f32 = params.xy(&perlin,&(x as f64),&(z as f64)) as f32;
-                vertecies.push(model::object::Vertex{position:(x,y*10.0,z)});
-            }        
+        WorldMap::with_seed(params, params.seed_or_random())
+    }
+
+    pub fn with_seed(params: MapParameters, seed: u32) -> WorldMap{
+        let source = Source::new(params.noise, seed);
+        WorldMap::from_source(params, seed, &source)
+    }
+
+    // Fills the map from any height source, e.g. a Graph built from a
+    // recipe. The source still goes through MapParameters::xy, so use a
+    // single level to sample it as is.
+    pub fn from_source<T: Sample + Sync>(mut params: MapParameters, seed: u32, source: &T) -> WorldMap{
+        params.seed = Some(seed);
+        // row major, rows run along z and columns along x
+        let (ox,oz) = params.origin;
+        let rows = map_rows(params.parallel, params.height as usize, |i|{
+            (0..params.width).map(|j|{
+                let x: f32 = ((ox + j) as f32) * params.scale;
+                let z: f32 = ((oz + i as i32) as f32) * params.scale;
+                let y: f32 = params.xy(source,&(x as f64),&(z as f64)) as f32;
+                model::object::Vertex{position:(x,y*10.0,z)}
+            }).collect::<Vec<_>>()
+        });
+        WorldMap{
+            parameters: params,
+            seed,
+            vertecies: rows.concat(),
+            layers: Layers::new(params.width as usize, params.height as usize),
+            apron: None,
         }
+    }
+
+    // Like from_source, but also samples one cell beyond every edge and
+    // keeps those heights as the apron
+    pub fn from_source_with_apron<T: Sample + Sync>(mut params: MapParameters, seed: u32, source: &T) -> WorldMap{
+        params.seed = Some(seed);
+        let (w,h) = (params.width as usize, params.height as usize);
+        let padded = WorldMap::from_source(params.region((params.origin.0 - 1, params.origin.1 - 1), params.width + 2, params.height + 2), seed, source);
+        // the middle of the padded window holds the map itself
+        let vertecies = (0..h).flat_map(|i|{
+            let row = (i + 1)*(w + 2) + 1;
+            padded.vertecies[row..row + w].iter().cloned()
+        }).collect();
         WorldMap{
             parameters: params,
-            seed: seed,
-            vertecies: vertecies
+            seed,
+            vertecies,
+            layers: Layers::new(w, h),
+            apron: Some(padded.heights()),
         }
     }
     
-    fn height_map (&self) -> Vec<f32> {
-        let mut vertecies = Vec::with_capacity((self.parameters.width *
-                                                self.parameters.height) as usize);
-        for i in 0..self.parameters.height{
-            for j in 0..self.parameters.width{
-                let model::object::Vertex{position: (_,_,z)}=
-                    self.vertecies[(i*self.parameters.width + j) as usize];
-                vertecies.push(z)
-            }
+    pub fn heights(&self) -> Vec<f32>{
+        self.vertecies.iter().map(|v| v.position.1).collect()
+    }
+
+    pub fn with_heights(&self, heights: Vec<f32>) -> WorldMap{
+        let vertecies = self.vertecies.iter().zip(heights).map(|(v,y)|{
+            let (x,_,z) = v.position;
+            model::object::Vertex{position:(x,y,z)}
+        }).collect();
+        // layers are carried over as they are, anything derived from the old
+        // heights has to be worked out again by the caller. The apron no
+        // longer matches the edges, so it is dropped.
+        WorldMap{
+            parameters: self.parameters,
+            seed: self.seed,
+            vertecies,
+            layers: self.layers.clone(),
+            apron: None,
         }
-        vertecies
     }
-    
+
     #[allow(dead_code)]
-    pub fn visualize(&self,filename: std::string::String){
-        let heights = normalize(self.height_map());
-        let height = self.parameters.height;
-        let width = self.parameters.width;
-        let img = ImageBuffer::from_fn(height.clone() as u32,width.clone() as u32,|x,y|{
-            image::Luma([heights[((width.clone() as u32)*y+x) as usize] as u8])
-        });
-        let name = filename;
-        let path = Path::new(&name);
-        img.save(path).unwrap(); // DANGEROUS!! Handle Properly
+    pub fn visualize(&self,filename: std::string::String) -> Result<(),MapError>{
+        self.export(filename, HeightFormat::Png8)
     }
     
     pub fn as_model_object(&self) -> model::object::Model{
+        self.as_shaded_model_object(Shading::Smooth)
+    }
+
+    pub fn as_shaded_model_object(&self, shading: Shading) -> model::object::Model{
         let width = self.parameters.width;
         let height = self.parameters.height;
-        // define indicies
-        let mut ind = Vec::with_capacity(((width - 1)*(height - 1)*3*2) as usize);
-        let mut norms = Vec::with_capacity((width * height ) as usize);
-        let zero_norm = model::object::Normal{normal:(0.0f32,0.0,0.0)};
-        for _i in 0..(width*height){
-            norms.push(zero_norm);
-        }
-        for i in 0..(height -1){
+        let parallel = self.parameters.parallel;
+        let cells = (height - 1).max(0) as usize;
+        // define indicies, one row of cells at a time
+        let ind = map_rows(parallel, cells, |i|{
+            let mut row = Vec::with_capacity(((width - 1)*6) as usize);
             for j in 0..(width -1){
-                let offset = width*i+j;
-                let mut x = vec!((offset+1) as u16,
-                                 (offset+width+1) as u16,
-                                 offset as u16);
-                let mut y = vec!(offset as u16,                                 
-                                 (offset+width+1) as u16,
-                                 (offset+width) as u16,);
-                let mut normal = cross_product(&self.vertecies[x[0] as usize],
-                                               &self.vertecies[x[1] as usize],
-                                               &self.vertecies[x[2] as usize]);
-                for n in x.clone(){                    
-                    norms[n as usize]=normal;
-                }
-                for n in y.clone(){
-                    norms[n as usize]=normal;
-                }                                
-
-                ind.append(&mut x);
-                ind.append(&mut y);
+                let offset = width*i as i32+j;
+                row.extend_from_slice(&[(offset+1) as u32,
+                                        (offset+width+1) as u32,
+                                        offset as u32,
+                                        offset as u32,
+                                        (offset+width+1) as u32,
+                                        (offset+width) as u32]);
             }
-        }
+            row
+        }).concat();
+        let faces: Vec<(f32,f32,f32)> = map_rows(parallel, cells, |i|{
+            let row = (width - 1) as usize*6;
+            ind[i*row..(i + 1)*row].chunks(3).map(|t|{
+                cross_product(&self.vertecies[t[0] as usize],
+                              &self.vertecies[t[1] as usize],
+                              &self.vertecies[t[2] as usize]).normal
+            }).collect::<Vec<_>>()
+        }).concat();
+        // biome colours when the map has a biome layer
+        let colors: Option<Vec<model::object::Color>> = self.layers.category8(BIOME).map(|biomes|{
+            biomes.iter().map(|&biome|{
+                let [r,g,b] = Biome::from_index(biome).map_or([128, 128, 128], |b| b.color());
+                model::object::Color{color:(r as f32/255.0, g as f32/255.0, b as f32/255.0)}
+            }).collect()
+        });
+        let (vertices, norms, colors, ind) = match shading{
+            Shading::Smooth => {
+                // sum the area weighted face normals around each vertex, the
+                // six triangles touching it taken in index order
+                let (ox,oz) = self.parameters.origin;
+                let scale = self.parameters.scale;
+                let outside = |apron: &[f32], r: i32, c: i32| model::object::Vertex{
+                    position:((ox + c) as f32*scale, apron[((r + 1)*(width + 2) + c + 1) as usize], (oz + r) as f32*scale)
+                };
+                let face = |r: i32, c: i32, k: usize| -> Option<(f32,f32,f32)>{
+                    if r >= 0 && c >= 0 && r < height - 1 && c < width - 1{
+                        return Some(faces[((r*(width - 1) + c)*2) as usize + k]);
+                    }
+                    // cells past the edge are built from the apron, wound
+                    // the same way as the ones inside
+                    self.apron.as_ref().filter(|_| r >= -1 && c >= -1 && r < height && c < width).map(|apron|{
+                        let v = |dr: i32, dc: i32| outside(apron, r + dr, c + dc);
+                        if k == 0{
+                            cross_product(&v(0,1), &v(1,1), &v(0,0)).normal
+                        } else {
+                            cross_product(&v(0,0), &v(1,1), &v(1,0)).normal
+                        }
+                    })
+                };
+                let norms = map_rows(parallel, height as usize, |i|{
+                    let i = i as i32;
+                    (0..width).map(|j|{
+                        let around = [face(i - 1,j - 1,0), face(i - 1,j - 1,1), face(i - 1,j,1),
+                                      face(i,j - 1,0), face(i,j,0), face(i,j,1)];
+                        let mut sum = (0.0f32,0.0f32,0.0f32);
+                        for &(nx,ny,nz) in around.iter().flat_map(|f| f.iter()){
+                            sum.0 += nx;
+                            sum.1 += ny;
+                            sum.2 += nz;
+                        }
+                        model::object::Normal{normal:unit(sum)}
+                    }).collect::<Vec<_>>()
+                }).concat();
+                (self.vertecies.clone(), norms, colors, ind)
+            },
+            Shading::Flat => {
+                // every triangle gets its own vertices so normals are not shared
+                let row = (width - 1).max(0) as usize*6;
+                let (vertices, norms): (Vec<_>,Vec<_>) = map_rows(parallel, cells, |i|{
+                    ind[i*row..(i + 1)*row].iter().enumerate().map(|(n,&v)|{
+                        let normal = faces[(i*row + n)/3];
+                        (self.vertecies[v as usize], model::object::Normal{normal:unit(normal)})
+                    }).collect::<Vec<_>>()
+                }).concat().into_iter().unzip();
+                let colors = colors.map(|c| ind.iter().map(|&n| c[n as usize]).collect());
+                let flat = (0..vertices.len() as u32).collect();
+                (vertices, norms, colors, flat)
+            }
+        };
         model::object::Model{
-            vertices: self.vertecies.clone(),
+            vertices,
             normals: Some(norms),
+            colors,
             index: Some(ind),
             material: None,
             bones: None,
         }
-        
     }
 }
 
+// Runs f over rows 0..n, spread across threads when parallel. Rows come back
+// in order either way.
+fn map_rows<T,F>(parallel: bool, n: usize, f: F) -> Vec<T>
+    where T: Send, F: Fn(usize) -> T + Sync + Send{
+    if parallel{
+        (0..n).into_par_iter().map(f).collect()
+    } else {
+        (0..n).map(f).collect()
+    }
+}
+
+// Brings loaded to exactly the keys in wanted, generating the ones that are
+// missing and dropping the ones that are no longer wanted. Returns the keys
+// that were added and removed. Shared by the heightfield and volume chunks.
+fn stream<K,V,F>(loaded: &mut HashMap<K,V>, wanted: &[K], generate: F) -> (Vec<K>,Vec<K>)
+    where K: Eq + Hash + Copy, F: Fn(K) -> V{
+    let keep: HashSet<K> = wanted.iter().cloned().collect();
+    let removed: Vec<K> = loaded.keys().filter(|k| !keep.contains(k)).cloned().collect();
+    for key in removed.iter(){
+        loaded.remove(key);
+    }
+    let mut added = Vec::new();
+    for &key in wanted{
+        if let Entry::Vacant(slot) = loaded.entry(key){
+            slot.insert(generate(key));
+            added.push(key);
+        }
+    }
+    (added,removed)
+}
+
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum Shading {
+    Smooth,
+    Flat,
+}
+
+// Face normal of the triangle abc, its length is twice the triangle's area
 fn cross_product(a: &model::object::Vertex, b: &model::object::Vertex, c: &model::object::Vertex) ->  model::object::Normal{
-    let (ax,ay,az) = a.uniform();
-    let (bx,by,bz) = b.uniform();
-    let (cx,cy,cz) = c.uniform();
+    let (ax,ay,az) = a.position;
+    let (bx,by,bz) = b.position;
+    let (cx,cy,cz) = c.position;
     let (abx,aby,abz) = (ax - bx, ay - by, az - bz);
     let (cbx,cby,cbz) = (cx - bx, cy - by, cz - bz);    
     let norm = ((aby*cbz - abz*cby),
@@ -146,13 +524,28 @@ fn cross_product(a: &model::object::Vertex, b: &model::object::Vertex, c: &model
     model::object::Normal{normal:norm}
 }
 
-#[allow(dead_code)]
-fn normalize(vector : Vec<f32>)->Vec<u8>{
+fn seeded_rng(seed: u32) -> XorShiftRng{
+    XorShiftRng::from_seed([seed, seed ^ 0x9E37_79B9, 0x85EB_CA6B, 0xC2B2_AE35])
+}
+
+fn unit(v: (f32,f32,f32)) -> (f32,f32,f32){
+    let (x,y,z) = v;
+    let mag = (x*x + y*y + z*z).sqrt();
+    if mag == 0.0{
+        (0.0,1.0,0.0)
+    } else{
+        (x/mag,y/mag,z/mag)
+    }
+}
+
+// rescale to 0..1, a flat vector maps to all zeros
+fn normalize(vector : &[f32])->Vec<f32>{
     let max = vector.iter().cloned().fold(0./0., f32::max);
     let min = vector.iter().cloned().fold(0./0., f32::min);
+    let range = if max > min {max - min} else {1.0};
     vector.iter().map(|v|{
-        ((v - min) / (max - min) * 256.0) as u8
-    }).collect::<Vec<u8>>()
+        (v - min) / range
+    }).collect::<Vec<f32>>()
 }
 
 #[allow(dead_code)]
@@ -161,6 +554,157 @@ fn batch_gen(height: i32, width: i32){
     for i in 0..5{
         let wm = WorldMap::new(params.clone());
         let file = format!("test_{}.png",i.to_string());
-        wm.visualize(file)
+        if let Err(e) = wm.visualize(file){
+            println!("{}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // values read off noise 0.6, a noise upgrade that changes them has to be
+    // looked at before they are updated
+    #[test]
+    fn seeded_maps_are_reproducible(){
+        let params = MapParameters::new(16, 16, 1.0, 4, 0.5, 2.0);
+        let map = WorldMap::with_seed(params, 42);
+        let heights = map.heights();
+        for &(k, expected) in [(17, 0.23676032), (100, 3.1815612), (255, -1.8003284)].iter(){
+            assert!((heights[k] - expected).abs() < 1e-5, "height {} is {}", k, heights[k]);
+        }
+        let same = |a: &WorldMap, b: &WorldMap| a.vertecies.iter().zip(b.vertecies.iter()).all(|(u,v)|{
+            u.position.0.to_bits() == v.position.0.to_bits()
+                && u.position.1.to_bits() == v.position.1.to_bits()
+                && u.position.2.to_bits() == v.position.2.to_bits()
+        });
+        assert!(same(&map, &WorldMap::with_seed(params, 42)));
+        assert!(same(&map, &WorldMap::new(params.seeded(42))));
+        assert!(!same(&map, &WorldMap::with_seed(params, 43)));
+    }
+
+    #[test]
+    fn large_map_indices_are_in_range(){
+        let (w, h) = (1024, 1024);
+        let model = WorldMap::with_seed(MapParameters::new(w, h, 1.0, 1, 0.5, 2.0), 1).as_model_object();
+        let index = model.index.as_ref().unwrap();
+        assert_eq!(index.len(), ((w - 1)*(h - 1)*6) as usize);
+        assert!(index.iter().all(|&i| (i as usize) < model.vertices.len()));
+    }
+
+    fn close(a: (f32,f32,f32), b: (f32,f32,f32)) -> bool{
+        (a.0 - b.0).abs() < 1e-3 && (a.1 - b.1).abs() < 1e-3 && (a.2 - b.2).abs() < 1e-3
+    }
+
+    #[test]
+    fn smooth_normals_on_planes(){
+        let map = WorldMap::with_seed(MapParameters::new(9, 7, 1.0, 1, 0.5, 2.0), 1);
+        let flat = map.with_heights(vec![0.0; 9*7]).as_model_object();
+        assert!(flat.normals.unwrap().iter().all(|n| close(n.normal, (0.0, 1.0, 0.0))));
+        let slope = map.with_heights(map.vertecies.iter().map(|v| v.position.0).collect()).as_model_object();
+        let r = std::f32::consts::FRAC_1_SQRT_2;
+        assert!(slope.normals.unwrap().iter().all(|n| close(n.normal, (-r, r, 0.0))));
+    }
+
+    #[test]
+    fn flat_shading_gives_every_index_its_own_vertex(){
+        let model = WorldMap::with_seed(MapParameters::new(9, 7, 1.0, 2, 0.5, 2.0), 1)
+            .as_shaded_model_object(Shading::Flat);
+        let index = model.index.as_ref().unwrap();
+        assert_eq!(model.vertices.len(), index.len());
+        assert_eq!(model.normals.as_ref().unwrap().len(), index.len());
+        assert!(index.iter().enumerate().all(|(k,&i)| i as usize == k));
+    }
+
+    #[test]
+    fn parallel_and_serial_maps_are_identical(){
+        let params = MapParameters::new(96, 80, 1.0, 4, 0.5, 2.0);
+        let serial = WorldMap::with_seed(params.with_parallel(false), 9);
+        let parallel = WorldMap::with_seed(params.with_parallel(true), 9);
+        let bits = |v: &[f32]| v.iter().map(|h| h.to_bits()).collect::<Vec<_>>();
+        assert_eq!(bits(&serial.heights()), bits(&parallel.heights()));
+        for &shading in [Shading::Smooth, Shading::Flat].iter(){
+            let (a, b) = (serial.as_shaded_model_object(shading), parallel.as_shaded_model_object(shading));
+            let normals = |m: &model::object::Model| m.normals.as_ref().unwrap().iter()
+                .flat_map(|n| vec![n.normal.0, n.normal.1, n.normal.2]).collect::<Vec<_>>();
+            assert_eq!(bits(&normals(&a)), bits(&normals(&b)));
+            assert_eq!(a.index, b.index);
+        }
+    }
+
+    #[test]
+    fn chunk_borders_shade_the_same(){
+        let chunks = Chunks::new(MapParameters::new(128, 128, 1.0, 6, 0.5, 2.0).seeded(3), 16, 1);
+        let (a, b) = (chunks.generate((0,0)), chunks.generate((1,0)));
+        let (na, nb) = (a.as_model_object().normals.unwrap(), b.as_model_object().normals.unwrap());
+        for i in 0..17{
+            assert_eq!(a.vertecies[i*17 + 16].position, b.vertecies[i*17].position);
+            assert_eq!(na[i*17 + 16].normal, nb[i*17].normal);
+        }
+    }
+
+    #[test]
+    fn chunk_borders_share_a_climate(){
+        let chunks = Chunks::new(MapParameters::new(128, 128, 1.0, 6, 0.5, 2.0).seeded(3), 16, 1)
+            .with_climate(ClimateParameters::new((-60.0, 60.0), (1.0, 0.0)));
+        let (a, b) = (chunks.generate((0,0)), chunks.generate((1,0)));
+        let (ba, bb) = (a.layers.category8(BIOME).unwrap(), b.layers.category8(BIOME).unwrap());
+        let (ma, mb) = (a.layers.scalar(MOISTURE).unwrap(), b.layers.scalar(MOISTURE).unwrap());
+        for i in 0..17{
+            assert_eq!(ba[i*17 + 16], bb[i*17]);
+            assert!((ma[i*17 + 16] - mb[i*17]).abs() < 1e-6);
+        }
+        assert_eq!(a.vertecies.len(), 17*17);
+    }
+
+    #[test]
+    fn latitude_repeats_past_the_extent(){
+        let params = MapParameters::new(8, 8, 1.0, 1, 0.5, 2.0).seeded(1);
+        let climate = ClimateParameters::new((-60.0, 60.0), (0.0, 0.0));
+        // under the sea temperature only follows latitude
+        let temperature = |z: i32|{
+            let map = WorldMap::new(params.region((0, z), 8, 8));
+            let map = map.with_heights(vec![-100.0; 64]).with_climate(&climate);
+            map.layers.scalar(TEMPERATURE).unwrap().to_vec()
+        };
+        let (first, again, back) = (temperature(0), temperature(14), temperature(7));
+        for i in 0..8{
+            assert!((again[i*8] - first[i*8]).abs() < 1e-4);
+            assert!((back[i*8] - first[(7 - i)*8]).abs() < 1e-4);
+        }
+    }
+
+    #[test]
+    fn layers_must_fit_the_map(){
+        let map = WorldMap::new(MapParameters::new(4, 3, 1.0, 1, 0.5, 2.0).seeded(1));
+        assert!(map.with_layer(SEDIMENT, Layer::Scalar(vec![0.0; 11])).is_err());
+        assert!(map.with_layer(HEIGHT, Layer::Category8(vec![0; 12])).is_err());
+        let map = map.with_layer(SEDIMENT, Layer::Scalar(vec![1.0; 12])).unwrap();
+        assert_eq!(map.layers.scalar(SEDIMENT), Some(&[1.0; 12][..]));
+        let map = map.with_layer(HEIGHT, Layer::Scalar(vec![2.0; 12])).unwrap();
+        assert_eq!(map.heights(), vec![2.0; 12]);
+        assert!(map.layers.get(HEIGHT).is_none());
+    }
+
+    #[test]
+    fn wrapped_maps_match_on_opposite_edges(){
+        let params = MapParameters::new(65, 33, 1.5, 6, 0.5, 2.0)
+            .with_octave_transform(30.0, (0.3, 0.7));
+        let torus = WorldMap::with_seed(params.with_wrap(Wrap::Torus).with_warp(Warp::new(0.2, 2)), 7);
+        let cylinder = WorldMap::with_seed(params.with_wrap(Wrap::Cylinder), 7);
+        let (w, h) = (65, 33);
+        for map in [&torus, &cylinder].iter(){
+            let heights = map.heights();
+            for i in 0..h{
+                assert_eq!(heights[i*w], heights[i*w + w - 1]);
+            }
+        }
+        let heights = torus.heights();
+        for j in 0..w{
+            assert_eq!(heights[j], heights[(h - 1)*w + j]);
+        }
+        let heights = cylinder.heights();
+        assert!((0..w).any(|j| heights[j] != heights[(h - 1)*w + j]));
     }
 }
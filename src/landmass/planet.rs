@@ -0,0 +1,185 @@
+use std::collections::HashMap;
+use std::path::Path;
+
+use super::{MapParameters,Source,Sample,HeightFormat,MapError,model,cross_product,unit,map_rows};
+use super::heightmap::write_samples;
+
+#[derive(Debug,Clone,Copy)]
+pub struct PlanetParameters {
+    // vertices along each edge of a face
+    pub resolution: usize,
+    pub radius: f32,
+    // noise is read at direction*frequency, so this sets how many features
+    // wrap round the planet
+    pub frequency: f64,
+    // radial displacement per unit of noise
+    pub amplitude: f32,
+}
+
+impl PlanetParameters {
+    pub fn new(resolution: usize, radius: f32) -> PlanetParameters{
+        PlanetParameters{resolution, radius, frequency: 2.0, amplitude: radius*0.05}
+    }
+}
+
+// One side of the cube. Points on it are normal + a*right + b*up for a and
+// b running from -1 to 1 along rows and columns.
+#[derive(Debug,Clone)]
+pub struct Face {
+    pub normal: [i32;3],
+    pub right: [i32;3],
+    pub up: [i32;3],
+    // row major like a WorldMap, rows run along up
+    pub heights: Vec<f32>,
+}
+
+const FACES: [([i32;3],[i32;3],[i32;3]);6] = [
+    ([1,0,0], [0,0,-1], [0,1,0]),
+    ([-1,0,0], [0,0,1], [0,1,0]),
+    ([0,1,0], [1,0,0], [0,0,-1]),
+    ([0,-1,0], [1,0,0], [0,0,1]),
+    ([0,0,1], [1,0,0], [0,1,0]),
+    ([0,0,-1], [-1,0,0], [0,1,0]),
+];
+
+// A planet built on a cube-sphere. Each face is a grid on the cube pushed
+// out onto the sphere, heights displace it along the radius.
+#[derive(Debug,Clone)]
+pub struct Planet {
+    pub parameters: PlanetParameters,
+    pub seed: u32,
+    pub faces: Vec<Face>,
+}
+
+impl Planet {
+    pub fn new(map: MapParameters, planet: PlanetParameters) -> Planet{
+        let seed = map.seed_or_random();
+        Planet::from_source(map, planet, seed, &Source::new(map.noise, seed))
+    }
+
+    // The fractal settings of map are used for the fBm, the grid settings
+    // are ignored
+    pub fn from_source<T: Sample + Sync>(map: MapParameters, planet: PlanetParameters, seed: u32, source: &T) -> Planet{
+        let n = planet.resolution.max(2);
+        let faces = FACES.iter().map(|&(normal, right, up)|{
+            let rows = map_rows(map.parallel, n, |i|{
+                (0..n).map(|j|{
+                    let d = direction(lattice(normal, right, up, i, j, n), n);
+                    let p = [d[0]*planet.frequency, d[1]*planet.frequency, d[2]*planet.frequency];
+                    map.xyz(source, p) as f32*planet.amplitude
+                }).collect::<Vec<_>>()
+            });
+            Face{normal, right, up, heights: rows.concat()}
+        }).collect();
+        Planet{parameters: planet, seed, faces}
+    }
+
+    // Height above the radius in a direction, read bilinearly off the face
+    // the direction passes through
+    pub fn height(&self, d: [f64;3]) -> f32{
+        let n = self.parameters.resolution.max(2);
+        let axis = (0..3).fold(0, |a, k| if d[k].abs() > d[a].abs() {k} else {a});
+        let face = self.faces.iter()
+            .find(|f| f.normal[axis] != 0 && (f.normal[axis] > 0) == (d[axis] > 0.0))
+            .unwrap();
+        let c = [d[0]/d[axis].abs(), d[1]/d[axis].abs(), d[2]/d[axis].abs()];
+        let dot = |v: [i32;3]| c[0]*v[0] as f64 + c[1]*v[1] as f64 + c[2]*v[2] as f64;
+        let last = (n - 1) as f64;
+        let x = ((dot(face.right) + 1.0)*0.5*last).max(0.0).min(last);
+        let y = ((dot(face.up) + 1.0)*0.5*last).max(0.0).min(last);
+        let (c0, r0) = ((x as usize).min(n - 2), (y as usize).min(n - 2));
+        let (s, t) = ((x - c0 as f64) as f32, (y - r0 as f64) as f32);
+        let h = |r: usize, c: usize| face.heights[r*n + c];
+        let top = h(r0,c0)*(1.0 - s) + h(r0,c0 + 1)*s;
+        let bottom = h(r0 + 1,c0)*(1.0 - s) + h(r0 + 1,c0 + 1)*s;
+        top*(1.0 - t) + bottom*t
+    }
+
+    // One mesh for the whole sphere. Vertices on the seams are shared
+    // between faces, so normals are smooth across them.
+    pub fn as_model_object(&self) -> model::object::Model{
+        let n = self.parameters.resolution.max(2);
+        let radius = self.parameters.radius;
+        let mut shared: HashMap<[i32;3],u32> = HashMap::new();
+        let mut vertices = Vec::new();
+        let mut ind = Vec::with_capacity(6*(n - 1)*(n - 1)*6);
+        for face in self.faces.iter(){
+            let mut index = |i: usize, j: usize| -> u32{
+                let key = lattice(face.normal, face.right, face.up, i, j, n);
+                *shared.entry(key).or_insert_with(||{
+                    let d = direction(key, n);
+                    let r = radius + face.heights[i*n + j];
+                    vertices.push(model::object::Vertex{position:((d[0]*r as f64) as f32,
+                                                                  (d[1]*r as f64) as f32,
+                                                                  (d[2]*r as f64) as f32)});
+                    (vertices.len() - 1) as u32
+                })
+            };
+            for i in 0..(n - 1){
+                for j in 0..(n - 1){
+                    let (a, b, c, d) = (index(i,j), index(i,j + 1), index(i + 1,j + 1), index(i + 1,j));
+                    ind.extend_from_slice(&[b, c, a, a, c, d]);
+                }
+            }
+        }
+        // keep every triangle facing outwards, the same way round as the
+        // terrain mesh
+        for t in ind.chunks_mut(3){
+            let normal = cross_product(&vertices[t[0] as usize], &vertices[t[1] as usize], &vertices[t[2] as usize]).normal;
+            let (x,y,z) = vertices[t[0] as usize].position;
+            if normal.0*x + normal.1*y + normal.2*z < 0.0{
+                t.swap(1, 2);
+            }
+        }
+        let mut sums = vec![(0.0f32,0.0f32,0.0f32); vertices.len()];
+        for t in ind.chunks(3){
+            let (nx,ny,nz) = cross_product(&vertices[t[0] as usize], &vertices[t[1] as usize], &vertices[t[2] as usize]).normal;
+            for &v in t{
+                let sum = &mut sums[v as usize];
+                sum.0 += nx;
+                sum.1 += ny;
+                sum.2 += nz;
+            }
+        }
+        model::object::Model{
+            normals: Some(sums.into_iter().map(|n| model::object::Normal{normal:unit(n)}).collect()),
+            vertices,
+            colors: None,
+            index: Some(ind),
+            material: None,
+            bones: None,
+        }
+    }
+
+    // Heights unwrapped onto a width x height latitude/longitude grid, north
+    // (+y) on the first row and longitude 0 facing +z in the middle column
+    pub fn export_equirectangular<P: AsRef<Path>>(&self, path: P, width: usize, height: usize, format: HeightFormat) -> Result<(),MapError>{
+        let samples = map_rows(true, height, |i|{
+            let lat = std::f64::consts::PI*(0.5 - (i as f64 + 0.5)/height as f64);
+            (0..width).map(|j|{
+                let lon = std::f64::consts::PI*(2.0*(j as f64 + 0.5)/width as f64 - 1.0);
+                self.height([lat.cos()*lon.sin(), lat.sin(), lat.cos()*lon.cos()])
+            }).collect::<Vec<_>>()
+        }).concat();
+        write_samples(path, width as u32, height as u32, &samples, format)
+    }
+}
+
+// Integer position of vertex (i,j) of a face on a cube of side 2*(n-1).
+// Faces meeting at an edge give their shared vertices the same lattice
+// point, which is what keeps the seams closed.
+fn lattice(normal: [i32;3], right: [i32;3], up: [i32;3], i: usize, j: usize, n: usize) -> [i32;3]{
+    let last = (n - 1) as i32;
+    let a = 2*j as i32 - last;
+    let b = 2*i as i32 - last;
+    [normal[0]*last + right[0]*a + up[0]*b,
+     normal[1]*last + right[1]*a + up[1]*b,
+     normal[2]*last + right[2]*a + up[2]*b]
+}
+
+fn direction(p: [i32;3], n: usize) -> [f64;3]{
+    let last = (n - 1) as f64;
+    let (x, y, z) = (p[0] as f64/last, p[1] as f64/last, p[2] as f64/last);
+    let len = (x*x + y*y + z*z).sqrt();
+    [x/len, y/len, z/len]
+}
@@ -0,0 +1,152 @@
+use std::cmp::Ordering;
+
+use super::WorldMap;
+
+impl WorldMap {
+    fn range(&self) -> (f32,f32){
+        self.vertecies.iter().fold((f32::INFINITY, f32::NEG_INFINITY), |(lo,hi), v|{
+            (lo.min(v.position.1), hi.max(v.position.1))
+        })
+    }
+
+    // Cuts the height range into steps even shelves. Sharpness 0 leaves the
+    // slopes as they are, towards 1 each rise gets shorter and the shelves
+    // flatter until at 1 the map is pure steps.
+    pub fn terrace(&self, steps: usize, sharpness: f32) -> WorldMap{
+        let (lo,hi) = self.range();
+        if steps == 0 || hi <= lo{
+            return self.with_heights(self.heights());
+        }
+        let step = (hi - lo) as f64/steps as f64;
+        let levels: Vec<f64> = (0..(steps + 1)).map(|k| lo as f64 + k as f64*step).collect();
+        let heights = self.heights().iter().map(|&h| terrace(h as f64, &levels, sharpness as f64) as f32).collect();
+        self.with_heights(heights)
+    }
+
+    // Passes every height through a Curve drawn through (input, output)
+    // points.
+    pub fn remap(&self, points: &[(f32,f32)]) -> WorldMap{
+        let points: Vec<(f64,f64)> = points.iter().map(|&(x,y)| (x as f64, y as f64)).collect();
+        let curve = Curve::new(&points);
+        let heights = self.heights().iter().map(|&h| curve.at(h as f64) as f32).collect();
+        self.with_heights(heights)
+    }
+
+    // Raises the normalised height to exponent. Above 1 the lowlands spread
+    // out and peaks sharpen, below 1 the land bulks up into plateaus.
+    pub fn redistribute(&self, exponent: f32) -> WorldMap{
+        let (lo,hi) = self.range();
+        if hi <= lo{
+            return self.with_heights(self.heights());
+        }
+        let heights = self.heights().iter()
+            .map(|&h| lo + (hi - lo)*((h - lo)/(hi - lo)).powf(exponent))
+            .collect();
+        self.with_heights(heights)
+    }
+
+    pub fn clamp_heights(&self, min: f32, max: f32) -> WorldMap{
+        let heights = self.heights().iter().map(|&h| h.max(min).min(max)).collect();
+        self.with_heights(heights)
+    }
+}
+
+// Height of v on shelves at the given ascending levels. Between two levels
+// the ground rises as t^(1/(1 - sharpness)) of the way up, so sharpness 0
+// keeps the slope, 0.5 rises as t^2, and 1 jumps straight to the next
+// shelf. Below the first level and above the last v holds their heights.
+pub fn terrace(v: f64, levels: &[f64], sharpness: f64) -> f64{
+    if levels.len() < 2{
+        return v;
+    }
+    if v <= levels[0]{
+        return levels[0];
+    }
+    let sharpness = sharpness.clamp(0.0, 1.0);
+    for w in levels.windows(2){
+        let (lo,hi) = (w[0],w[1]);
+        if v <= hi{
+            let t = if hi > lo {(v - lo)/(hi - lo)} else {1.0};
+            let t = if sharpness >= 1.0 {if t >= 1.0 {1.0} else {0.0}} else {t.powf(1.0/(1.0 - sharpness))};
+            return lo + (hi - lo)*t;
+        }
+    }
+    levels[levels.len() - 1]
+}
+
+// A smooth curve through (input, output) points. It is a monotone cubic,
+// so it never overshoots between points, and it holds the end values
+// beyond them.
+#[derive(Debug,Clone)]
+pub struct Curve {
+    points: Vec<(f64,f64)>,
+    tangents: Vec<f64>,
+}
+
+impl Curve {
+    // points may come in any order, of points sharing an input the first
+    // is kept
+    pub fn new(points: &[(f64,f64)]) -> Curve{
+        let mut points = points.to_vec();
+        points.sort_by(|p, q| p.0.partial_cmp(&q.0).unwrap_or(Ordering::Equal));
+        points.dedup_by(|p, q| p.0 == q.0);
+        let tangents = monotone_tangents(&points);
+        Curve{points, tangents}
+    }
+
+    pub fn at(&self, v: f64) -> f64{
+        let (points, tangents) = (&self.points, &self.tangents);
+        if points.is_empty(){
+            return v;
+        }
+        if v <= points[0].0{
+            return points[0].1;
+        }
+        for (k,w) in points.windows(2).enumerate(){
+            let ((x0,y0),(x1,y1)) = (w[0],w[1]);
+            if v <= x1{
+                let dx = x1 - x0;
+                let t = (v - x0)/dx;
+                let (t2,t3) = (t*t, t*t*t);
+                return (2.0*t3 - 3.0*t2 + 1.0)*y0 + (t3 - 2.0*t2 + t)*dx*tangents[k]
+                    + (-2.0*t3 + 3.0*t2)*y1 + (t3 - t2)*dx*tangents[k + 1];
+            }
+        }
+        points[points.len() - 1].1
+    }
+}
+
+// Fritsch-Carlson slopes at each point, zeroed wherever the curve turns so
+// the segments stay monotone
+fn monotone_tangents(points: &[(f64,f64)]) -> Vec<f64>{
+    let n = points.len();
+    if n < 2{
+        return vec![0.0; n];
+    }
+    let secants: Vec<f64> = points.windows(2)
+        .map(|w| (w[1].1 - w[0].1)/(w[1].0 - w[0].0))
+        .collect();
+    let mut tangents = Vec::with_capacity(n);
+    tangents.push(secants[0]);
+    for k in 1..(n - 1){
+        let (a,b) = (secants[k - 1], secants[k]);
+        tangents.push(if a*b <= 0.0 {0.0} else {(a + b)*0.5});
+    }
+    tangents.push(secants[n - 2]);
+    for k in 0..(n - 1){
+        let d = secants[k];
+        if d == 0.0{
+            tangents[k] = 0.0;
+            tangents[k + 1] = 0.0;
+            continue;
+        }
+        let (a,b) = (tangents[k]/d, tangents[k + 1]/d);
+        let s = a*a + b*b;
+        if s > 9.0{
+            let t = 3.0/s.sqrt();
+            tangents[k] = t*a*d;
+            tangents[k + 1] = t*b*d;
+        }
+    }
+    tangents
+}
@@ -0,0 +1,69 @@
+use noise::{Perlin,OpenSimplex,Worley,Value,RidgedMulti,Seedable,NoiseFn};
+
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum NoiseType {
+    Perlin,
+    OpenSimplex,
+    Worley,
+    Value,
+    Ridged,
+}
+
+// Height source driven by the fBm loop in MapParameters::xy. Anything that
+// implements NoiseFn for 2D, 3D and 4D points can be used in its place.
+#[derive(Clone)]
+pub enum Source {
+    Perlin(Perlin),
+    OpenSimplex(OpenSimplex),
+    Worley(Worley),
+    Value(Value),
+    Ridged(RidgedMulti),
+}
+
+impl Source {
+    pub fn new(noise: NoiseType, seed: u32) -> Source{
+        match noise{
+            NoiseType::Perlin => Source::Perlin(Perlin::new().set_seed(seed)),
+            NoiseType::OpenSimplex => Source::OpenSimplex(OpenSimplex::new().set_seed(seed)),
+            NoiseType::Worley => Source::Worley(Worley::new().set_seed(seed)),
+            NoiseType::Value => Source::Value(Value::new().set_seed(seed)),
+            NoiseType::Ridged => Source::Ridged(RidgedMulti::new().set_seed(seed)),
+        }
+    }
+}
+
+impl NoiseFn<[f64;2]> for Source {
+    fn get(&self, point: [f64;2]) -> f64{
+        match *self{
+            Source::Perlin(ref n) => n.get(point),
+            Source::OpenSimplex(ref n) => n.get(point),
+            Source::Worley(ref n) => n.get(point),
+            Source::Value(ref n) => n.get(point),
+            Source::Ridged(ref n) => n.get(point),
+        }
+    }
+}
+
+impl NoiseFn<[f64;3]> for Source {
+    fn get(&self, point: [f64;3]) -> f64{
+        match *self{
+            Source::Perlin(ref n) => n.get(point),
+            Source::OpenSimplex(ref n) => n.get(point),
+            Source::Worley(ref n) => n.get(point),
+            Source::Value(ref n) => n.get(point),
+            Source::Ridged(ref n) => n.get(point),
+        }
+    }
+}
+
+impl NoiseFn<[f64;4]> for Source {
+    fn get(&self, point: [f64;4]) -> f64{
+        match *self{
+            Source::Perlin(ref n) => n.get(point),
+            Source::OpenSimplex(ref n) => n.get(point),
+            Source::Worley(ref n) => n.get(point),
+            Source::Value(ref n) => n.get(point),
+            Source::Ridged(ref n) => n.get(point),
+        }
+    }
+}
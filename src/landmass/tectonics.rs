@@ -0,0 +1,145 @@
+use noise::NoiseFn;
+use rand::Rng;
+use std::cmp::Ordering;
+
+use super::{WorldMap,Source,Layer,PLATE,UPLIFT,seeded_rng,map_rows};
+
+#[derive(Debug,Clone,Copy)]
+pub struct PlateParameters {
+    // the PLATE layer numbers plates in 16 bits, past 65536 the rest are
+    // dropped
+    pub plates: usize,
+    // share of plates carrying continental crust, the rest are ocean floor
+    pub continental: f32,
+    // continents sit this far above the base noise and ocean floor this far
+    // below it
+    pub shelf: f32,
+    // peak height of mountains where plates meet head on
+    pub uplift: f32,
+    // peak height of the ridges either side of a rift where plates pull apart
+    pub ridge: f32,
+    // cells over which boundary features fade out
+    pub width: f32,
+    // cells the plate boundaries are pushed about by noise, 0 leaves them as
+    // straight Voronoi edges
+    pub warp: f32,
+    pub seed: u32,
+}
+
+impl PlateParameters {
+    pub fn new(plates: usize, seed: u32) -> PlateParameters{
+        PlateParameters{
+            plates,
+            continental: 0.4,
+            shelf: 3.0,
+            uplift: 8.0,
+            ridge: 3.0,
+            width: 8.0,
+            warp: 10.0,
+            seed,
+        }
+    }
+}
+
+struct Plate {
+    center: (f32,f32),
+    velocity: (f32,f32),
+    continental: bool,
+}
+
+impl WorldMap {
+    // Splits the map into Voronoi plates drifting in random directions and
+    // adds the crust they carry and what happens where they meet: mountains
+    // along collisions, ridges split by a rift where they pull apart. The
+    // plate of each cell goes in the PLATE layer and the height added in
+    // UPLIFT.
+    pub fn with_tectonics(&self, params: &PlateParameters) -> WorldMap{
+        let (w,h) = (self.parameters.width as usize, self.parameters.height as usize);
+        if params.plates == 0 || w == 0 || h == 0{
+            return self.clone();
+        }
+        let count = params.plates.min(u16::MAX as usize + 1);
+        let mut rng = seeded_rng(params.seed);
+        let plates: Vec<Plate> = (0..count).map(|_|{
+            let angle = rng.gen_range(0.0, 2.0*std::f32::consts::PI);
+            let speed = rng.gen_range(0.0, 1.0);
+            Plate{
+                center: (rng.gen_range(0.0, w as f32), rng.gen_range(0.0, h as f32)),
+                velocity: (angle.cos()*speed, angle.sin()*speed),
+                continental: rng.gen::<f32>() < params.continental,
+            }
+        }).collect();
+        let warp = (Source::new(self.parameters.noise, params.seed), Source::new(self.parameters.noise, params.seed.wrapping_add(1)));
+        // warp noise has about one wobble per plate
+        let frequency = (count as f64/(w*h) as f64).sqrt();
+        let falloff = |d: f32| (-(d/params.width)*(d/params.width)).exp();
+        let rows = map_rows(self.parameters.parallel, h, |i|{
+            (0..w).map(|j|{
+                let (fx,fz) = (j as f64*frequency, i as f64*frequency);
+                let x = j as f32 + warp.0.get([fx, fz]) as f32*params.warp;
+                let z = i as f32 + warp.1.get([fx, fz]) as f32*params.warp;
+                let dist2 = |p: &Plate| (p.center.0 - x)*(p.center.0 - x) + (p.center.1 - z)*(p.center.1 - z);
+                let own = (0..plates.len())
+                    .min_by(|&a, &b| dist2(&plates[a]).partial_cmp(&dist2(&plates[b])).unwrap_or(Ordering::Equal))
+                    .unwrap();
+                let a = &plates[own];
+                let base = if a.continental {params.shelf} else {-params.shelf};
+                // distance to the nearest edge of the Voronoi cell, which is
+                // the bisector with one of the other plates
+                let edge = plates.iter().enumerate().filter(|&(k,_)| k != own).map(|(_,b)|{
+                    let span = ((b.center.0 - a.center.0).powi(2) + (b.center.1 - a.center.1).powi(2)).sqrt().max(1e-6);
+                    ((dist2(b) - dist2(a))/(2.0*span), b, span)
+                }).min_by(|p, q| p.0.partial_cmp(&q.0).unwrap_or(Ordering::Equal));
+                let (d, b, span) = match edge{
+                    Some(edge) => edge,
+                    None => return (own as u16, base)
+                };
+                // positive where the plates close on each other along the
+                // line between them, negative where they draw apart
+                let closing = ((a.velocity.0 - b.velocity.0)*(b.center.0 - a.center.0)
+                               + (a.velocity.1 - b.velocity.1)*(b.center.1 - a.center.1))/span;
+                let f = falloff(d);
+                // both sides meet at the mean of their crust on the boundary
+                let other = if b.continental {params.shelf} else {-params.shelf};
+                let crust = base*(1.0 - 0.5*f) + other*0.5*f;
+                let stress = if closing > 0.0{
+                    params.uplift*closing*f
+                } else {
+                    params.ridge*(-closing)*(f - falloff(d*3.0))
+                };
+                (own as u16, crust + stress)
+            }).collect::<Vec<_>>()
+        }).concat();
+        // the nearest boundary changes abruptly where three plates meet, a
+        // blur about half a boundary wide hides the seam
+        let radius = (params.width*0.5).round() as usize;
+        let raw: Vec<f32> = rows.iter().map(|&(_,u)| u).collect();
+        let uplift = blur(&blur(&raw, w, h, radius), w, h, radius);
+        let mut map = self.with_heights(self.heights().iter().zip(uplift.iter()).map(|(h,u)| h + u).collect());
+        map.layers.set(PLATE, Layer::Category16(rows.iter().map(|&(p,_)| p).collect()));
+        map.layers.set(UPLIFT, Layer::Scalar(uplift));
+        map
+    }
+}
+
+// Box blur of radius cells along rows then columns, clamped at the borders
+fn blur(values: &[f32], width: usize, height: usize, radius: usize) -> Vec<f32>{
+    if radius == 0{
+        return values.to_vec();
+    }
+    let pass = |values: &[f32], n: usize, stride: usize, lines: usize, step: usize| -> Vec<f32>{
+        let mut out = vec![0.0; values.len()];
+        for line in 0..lines{
+            let at = |k: usize| values[line*step + k*stride];
+            for k in 0..n{
+                let lo = k.saturating_sub(radius);
+                let hi = (k + radius).min(n - 1);
+                let sum: f32 = (lo..(hi + 1)).map(&at).sum();
+                out[line*step + k*stride] = sum/(hi + 1 - lo) as f32;
+            }
+        }
+        out
+    };
+    let rows = pass(values, width, 1, height, width);
+    pass(&rows, height, width, width, 1)
+}
@@ -0,0 +1,253 @@
+use noise::NoiseFn;
+use std::collections::HashMap;
+
+use super::{MapParameters,Source,model,unit,map_rows,stream};
+
+pub type VolumeKey = (i32,i32,i32);
+
+#[derive(Debug,Clone,Copy)]
+pub struct CaveParameters {
+    // noise above this carves open caverns
+    pub cheese_threshold: f64,
+    pub cheese_frequency: f64,
+    // tunnels run where two noise fields are both within this of zero
+    pub worm_radius: f64,
+    pub worm_frequency: f64,
+    // world units of rock removed per unit of cave noise
+    pub strength: f32,
+    // depth below the surface over which caves fade in, so most of them stay
+    // closed over
+    pub roof: f32,
+}
+
+impl CaveParameters {
+    pub fn new(cheese_threshold: f64, worm_radius: f64) -> CaveParameters{
+        CaveParameters{
+            cheese_threshold,
+            cheese_frequency: 0.04,
+            worm_radius,
+            worm_frequency: 0.02,
+            strength: 40.0,
+            roof: 4.0,
+        }
+    }
+}
+
+// Density sampled on a lattice, positive inside rock. A chunk of size cells
+// a side keeps one extra layer of samples either side, which is enough to
+// mesh every edge it owns including the ones on its border.
+#[derive(Debug,Clone)]
+pub struct Volume {
+    pub origin: VolumeKey,
+    pub size: usize,
+    pub scale: f32,
+    pub density: Vec<f32>,
+}
+
+// Everything that goes into the density function, shared by every chunk
+#[derive(Clone)]
+struct Field {
+    params: MapParameters,
+    caves: CaveParameters,
+    ground: Source,
+    cheese: Source,
+    worm: (Source,Source),
+}
+
+impl Field {
+    fn new(params: MapParameters, caves: CaveParameters, seed: u32) -> Field{
+        Field{
+            params,
+            caves,
+            ground: Source::new(params.noise, seed),
+            cheese: Source::new(params.noise, seed.wrapping_add(1)),
+            worm: (Source::new(params.noise, seed.wrapping_add(2)), Source::new(params.noise, seed.wrapping_add(3))),
+        }
+    }
+
+    fn density(&self, surface: f32, x: f64, y: f64, z: f64) -> f32{
+        let c = &self.caves;
+        let ground = surface - y as f32;
+        let cf = c.cheese_frequency;
+        let cheese = self.cheese.get([x*cf, y*cf, z*cf]) - c.cheese_threshold;
+        let wf = c.worm_frequency;
+        let (a,b) = (self.worm.0.get([x*wf, y*wf, z*wf]), self.worm.1.get([x*wf, y*wf, z*wf]));
+        let worm = c.worm_radius - a.abs().max(b.abs());
+        let depth = (ground/c.roof).clamp(0.0, 1.0);
+        ground.min(-(cheese.max(worm) as f32)*c.strength*depth + ground*(1.0 - depth))
+    }
+}
+
+impl Volume {
+    // Samples the chunk at key, a cube of size cells with its lowest corner
+    // at key*size. The surface comes from the same noise and fBm settings
+    // as the heightfield, caves are cut from it with 3D noise.
+    pub fn new(params: MapParameters, caves: CaveParameters, key: VolumeKey, size: usize) -> Volume{
+        let seed = params.seed_or_random();
+        Volume::sample(&Field::new(params, caves, seed), key, size)
+    }
+
+    fn sample(field: &Field, key: VolumeKey, size: usize) -> Volume{
+        let scale = field.params.scale;
+        let n = size + 3;
+        let s = size as i32;
+        let corner = (key.0*s - 1, key.1*s - 1, key.2*s - 1);
+        // the surface only depends on x and z
+        let surface: Vec<f32> = (0..n*n).map(|k|{
+            let x = ((corner.0 + (k % n) as i32) as f32*scale) as f64;
+            let z = ((corner.2 + (k / n) as i32) as f32*scale) as f64;
+            field.params.xy(&field.ground, &x, &z) as f32*10.0
+        }).collect();
+        let density = map_rows(field.params.parallel, n, |k|{
+            let z = (corner.2 + k as i32) as f32*scale;
+            let mut slab = Vec::with_capacity(n*n);
+            for j in 0..n{
+                let y = (corner.1 + j as i32) as f32*scale;
+                for i in 0..n{
+                    let x = (corner.0 + i as i32) as f32*scale;
+                    slab.push(field.density(surface[k*n + i], x as f64, y as f64, z as f64));
+                }
+            }
+            slab
+        }).concat();
+        Volume{origin: key, size, scale, density}
+    }
+
+    fn at(&self, x: usize, y: usize, z: usize) -> f32{
+        let n = self.size + 3;
+        self.density[(z*n + y)*n + x]
+    }
+
+    // Surface nets: one vertex per cell the surface passes through, placed
+    // at the mean of the crossings on its edges, and one quad per crossed
+    // lattice edge joining the four cells around it.
+    pub fn as_model_object(&self) -> model::object::Model{
+        let n = self.size + 3;
+        let cells = n - 1;
+        let s = self.size as i32;
+        // whole cells are added up front so neighbouring chunks place their
+        // shared vertices at exactly the same point
+        let world = |cell: usize, t: f32, o: i32| ((o*s - 1 + cell as i32) as f32 + t)*self.scale;
+        let corners = [(0,0,0),(1,0,0),(0,1,0),(1,1,0),(0,0,1),(1,0,1),(0,1,1),(1,1,1)];
+        let edges = [(0,1),(2,3),(4,5),(6,7),(0,2),(1,3),(4,6),(5,7),(0,4),(1,5),(2,6),(3,7)];
+        let mut vertex_of: HashMap<(usize,usize,usize),u32> = HashMap::new();
+        let mut vertices = Vec::new();
+        let mut normals = Vec::new();
+        for z in 0..cells{
+            for y in 0..cells{
+                for x in 0..cells{
+                    let d: Vec<f32> = corners.iter().map(|&(i,j,k)| self.at(x + i, y + j, z + k)).collect();
+                    if d.iter().all(|&v| v > 0.0) || d.iter().all(|&v| v <= 0.0){
+                        continue;
+                    }
+                    let mut sum = (0.0, 0.0, 0.0);
+                    let mut count = 0.0;
+                    for &(a,b) in edges.iter(){
+                        if (d[a] > 0.0) != (d[b] > 0.0){
+                            let t = d[a]/(d[a] - d[b]);
+                            let (pa,pb) = (corners[a], corners[b]);
+                            sum.0 += pa.0 as f32 + (pb.0 - pa.0) as f32*t;
+                            sum.1 += pa.1 as f32 + (pb.1 - pa.1) as f32*t;
+                            sum.2 += pa.2 as f32 + (pb.2 - pa.2) as f32*t;
+                            count += 1.0;
+                        }
+                    }
+                    // density falls towards open air, the normal points down the gradient
+                    let gx = (d[1] - d[0]) + (d[3] - d[2]) + (d[5] - d[4]) + (d[7] - d[6]);
+                    let gy = (d[2] - d[0]) + (d[3] - d[1]) + (d[6] - d[4]) + (d[7] - d[5]);
+                    let gz = (d[4] - d[0]) + (d[5] - d[1]) + (d[6] - d[2]) + (d[7] - d[3]);
+                    vertex_of.insert((x,y,z), vertices.len() as u32);
+                    vertices.push(model::object::Vertex{position:(world(x, sum.0/count, self.origin.0),
+                                                                  world(y, sum.1/count, self.origin.1),
+                                                                  world(z, sum.2/count, self.origin.2))});
+                    normals.push(model::object::Normal{normal:unit((-gx,-gy,-gz))});
+                }
+            }
+        }
+        // the chunk owns the edges starting at samples 1..=size, the extra
+        // layer of samples belongs to its neighbours
+        let mut ind = Vec::new();
+        for z in 1..(self.size + 1){
+            for y in 1..(self.size + 1){
+                for x in 1..(self.size + 1){
+                    let d0 = self.at(x,y,z);
+                    let steps = [((1,0,0),[(0,0,0),(0,1,0),(0,1,1),(0,0,1)]),
+                                 ((0,1,0),[(0,0,0),(0,0,1),(1,0,1),(1,0,0)]),
+                                 ((0,0,1),[(0,0,0),(1,0,0),(1,1,0),(0,1,0)])];
+                    for &((dx,dy,dz), ref around) in steps.iter(){
+                        let d1 = self.at(x + dx, y + dy, z + dz);
+                        if (d0 > 0.0) == (d1 > 0.0){
+                            continue;
+                        }
+                        let quad: Vec<u32> = around.iter()
+                            .map(|&(i,j,k)| vertex_of[&(x - i, y - j, z - k)])
+                            .collect();
+                        // rock on the low side faces the quad along +axis
+                        if d0 > 0.0{
+                            ind.extend_from_slice(&[quad[0], quad[2], quad[1], quad[0], quad[3], quad[2]]);
+                        } else {
+                            ind.extend_from_slice(&[quad[0], quad[1], quad[2], quad[0], quad[2], quad[3]]);
+                        }
+                    }
+                }
+            }
+        }
+        model::object::Model{
+            vertices,
+            normals: Some(normals),
+            colors: None,
+            index: Some(ind),
+            material: None,
+            bones: None,
+        }
+    }
+}
+
+// Streams volume chunks in a cube around a moving point, like Chunks does
+// for the heightfield
+pub struct VolumeChunks {
+    pub seed: u32,
+    field: Field,
+    size: usize,
+    radius: i32,
+    chunks: HashMap<VolumeKey,Volume>,
+}
+
+impl VolumeChunks {
+    pub fn new(params: MapParameters, caves: CaveParameters, size: usize, radius: i32) -> VolumeChunks{
+        let seed = params.seed_or_random();
+        VolumeChunks{
+            seed,
+            field: Field::new(params.seeded(seed), caves, seed),
+            size,
+            radius,
+            chunks: HashMap::new(),
+        }
+    }
+
+    pub fn key(&self, position: [f32;3]) -> VolumeKey{
+        let span = self.size as f32 * self.field.params.scale;
+        ((position[0] / span).floor() as i32,
+         (position[1] / span).floor() as i32,
+         (position[2] / span).floor() as i32)
+    }
+
+    pub fn get(&self, key: &VolumeKey) -> Option<&Volume>{
+        self.chunks.get(key)
+    }
+
+    pub fn generate(&self, key: VolumeKey) -> Volume{
+        Volume::sample(&self.field, key, self.size)
+    }
+
+    // Same as Chunks::update, over a cube of chunks
+    pub fn update(&mut self, position: [f32;3]) -> (Vec<VolumeKey>,Vec<VolumeKey>){
+        let (cx,cy,cz) = self.key(position);
+        let r = self.radius;
+        let wanted: Vec<VolumeKey> = ((cx - r)..(cx + r + 1)).flat_map(|x|{
+            ((cy - r)..(cy + r + 1)).flat_map(move |y| ((cz - r)..(cz + r + 1)).map(move |z| (x,y,z)))
+        }).collect();
+        let (field, size) = (&self.field, self.size);
+        stream(&mut self.chunks, &wanted, |key| Volume::sample(field, key, size))
+    }
+}
@@ -1,12 +1,19 @@
 #[macro_use]
 extern crate glium;
+#[macro_use]
+extern crate serde_derive;
+extern crate serde;
+extern crate ron;
+extern crate rayon;
 extern crate rand;
 extern crate image;
+extern crate png;
 extern crate noise;
 extern crate cgmath;
 
 use glium::{glutin,Surface};
 use std::{thread, time};
+use std::collections::HashMap;
 
 mod shaders;
 mod camera;
@@ -19,11 +26,35 @@ use shaders::{VERTEX_SHADER_SRC,FRAGMENT_SHADER_SRC};
 use camera::Camera;
 
 fn main() {
+    // landmass bench [size] [octaves] compares serial and parallel generation
+    if std::env::args().nth(1).map_or(false, |arg| arg == "bench"){
+        let arg = |n: usize, default: i32| std::env::args().nth(n).and_then(|a| a.parse().ok()).unwrap_or(default);
+        landmass::bench::run(arg(2, 2048), arg(3, 8), 1);
+        return;
+    }
     let height = 256;
     let width = 256;
+    // landmass [seed] [recipe.ron]
+    let recipe = std::env::args().nth(2).map(|path|{
+        landmass::Node::load(&path).unwrap_or_else(|e|{
+            eprintln!("could not load recipe {}: {}", path, e);
+            std::process::exit(1);
+        })
+    });
     let params = landmass::MapParameters::new(height.clone(),width.clone(),2.0,8,0.8,1.5);
-    let wm = landmass::WorldMap::new(params.clone());
-    let obj = wm.as_model_object();
+    let params = match std::env::args().nth(1).and_then(|arg| arg.parse().ok()){
+        Some(seed) => params.seeded(seed),
+        None => params
+    };
+    let chunk_size = 64;
+    let mut chunks = landmass::Chunks::new(params, chunk_size, 6);
+    if let Some(recipe) = recipe{
+        let graph = recipe.build(chunks.seed);
+        chunks = chunks.with_graph(graph);
+    }
+    chunks = chunks.with_climate(landmass::ClimateParameters::new((-60.0, 60.0), (1.0, 0.0)));
+    let lod = landmass::Lod::new(chunk_size + 1, 96.0);
+    println!("seed: {}", chunks.seed);
 
     let mut events_loop = glutin::EventsLoop::new();
     let window = glutin::WindowBuilder::new()
@@ -37,10 +68,9 @@ fn main() {
     gl_win.set_cursor_state(glutin::CursorState::Normal).unwrap();    
     let display = glium::Display::from_gl_window(gl_win).unwrap();
     
-    let positions = glium::VertexBuffer::new(&display,&obj.vertices).unwrap();
-    let normals = glium::VertexBuffer::new(&display,&(obj.normals.unwrap())).unwrap();
-    let indices = glium::IndexBuffer::new(&display, glium::index::PrimitiveType::TrianglesList, &(obj.index.unwrap())).unwrap();
-    
+    let mut meshes = HashMap::new();
+    let mut lod_indices = HashMap::new();
+
     let program = glium::Program::from_source(&display,
                                               &VERTEX_SHADER_SRC,
                                               &FRAGMENT_SHADER_SRC,
@@ -62,6 +92,21 @@ fn main() {
     let mut keydown = Keyboard::new();
     let mut previous = (0.0,0.0);
     while !closed {
+        let (added, removed) = chunks.update([cam.pos.x, cam.pos.y, cam.pos.z]);
+        for key in removed{
+            meshes.remove(&key);
+        }
+        for key in added{
+            let obj = chunks.get(&key).unwrap().as_model_object();
+            let positions = glium::VertexBuffer::new(&display,&obj.vertices).unwrap();
+            let normals = glium::VertexBuffer::new(&display,&(obj.normals.unwrap())).unwrap();
+            // maps without a climate get the old flat terrain colour
+            let colors = obj.colors.unwrap_or_else(||
+                vec![landmass::Color{color:(0.3, 0.05, 0.1)}; obj.vertices.len()]);
+            let colors = glium::VertexBuffer::new(&display,&colors).unwrap();
+            meshes.insert(key,(positions,normals,colors));
+        }
+
         let mut target = display.draw();
         let view = cam.view_matrix();
         let perspective = {
@@ -87,15 +132,22 @@ fn main() {
             [1.0, 0.0, 0.0, 0.0],
             [0.0, 1.0, 0.0, 0.0],
             [0.0, 0.0, 1.0, 0.0],
-            [-256.0, 0.0, -256.0, 1.0f32]
+            [0.0, 0.0, 0.0, 1.0f32]
         ];
 
-        target.draw((&positions, &normals), &indices, &program,
-                    &uniform! { model: model,
-                                u_light: light,
-                                view: view,
-                                perspective: perspective},
-                    &params).unwrap();
+        for (key, &(ref positions, ref normals, ref colors)) in meshes.iter(){
+            let lod_key = chunks.lod_key(&lod, *key, [cam.pos.x, cam.pos.y, cam.pos.z]);
+            if !lod_indices.contains_key(&lod_key){
+                let indices = glium::IndexBuffer::new(&display, glium::index::PrimitiveType::TrianglesList, &lod.indices(lod_key)).unwrap();
+                lod_indices.insert(lod_key, indices);
+            }
+            target.draw((positions, normals, colors), &lod_indices[&lod_key], &program,
+                        &uniform! { model: model,
+                                    u_light: light,
+                                    view: view,
+                                    perspective: perspective},
+                        &params).unwrap();
+        }
         target.finish().unwrap();
 
         events_loop.poll_events(|event| {
@@ -1,5 +1,6 @@
 implement_vertex!(Vertex, position);
 implement_vertex!(Normal, normal);
+implement_vertex!(Color, color);
 
 
 #[derive(Debug,Clone,Copy)]
@@ -8,6 +9,7 @@ pub struct Vertex {
 }
 
 impl Vertex{
+    #[allow(dead_code)]
     pub fn uniform (&self) -> (f32,f32,f32){
         let (x,y,z) = self.position;
         let mag2 = x*x + y*y + z*z;
@@ -25,11 +27,18 @@ pub struct Normal {
     pub normal: (f32,f32,f32)
 }
 
+// linear rgb, 0..1
+#[derive(Debug,Clone,Copy)]
+pub struct Color {
+    pub color: (f32,f32,f32)
+}
+
 #[derive(Debug,Clone)]
 pub struct Model{
     pub vertices : Vec<Vertex>,
     pub normals  : Option<Vec<Normal>>,
-    pub index    : Option<Vec<u16>>,
+    pub colors   : Option<Vec<Color>>,
+    pub index    : Option<Vec<u32>>,
     pub material : Option<&'static str>,    
     pub bones    : Option<bool> // add later
 }